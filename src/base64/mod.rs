@@ -1,3 +1,5 @@
+use std::error::Error;
+use std::fmt::Display;
 use std::ops::{Shl, Shr};
 
 pub fn encode(bytes: Vec<u8>) -> String {
@@ -10,17 +12,15 @@ pub fn encode(bytes: Vec<u8>) -> String {
 
         let first = translate_char(first_byte.shr(2));
         let second =
-            translate_char((0x30 as u8 & (first_byte.shl(4))) | second_byte.unwrap_or(&0).shr(4));
+            translate_char((0x30_u8 & (first_byte.shl(4))) | second_byte.unwrap_or(&0).shr(4));
         let third = match (second_byte, third_byte) {
             (Some(second_byte), Some(third_byte)) => {
-                translate_char(0x3F as u8 & (second_byte.shl(2) | third_byte.shr(6)))
-            }
-            (Some(second_byte), None) => {
-                translate_char(0x3F as u8 & (second_byte.shl(2) | 0x00 as u8))
+                translate_char(0x3F_u8 & (second_byte.shl(2) | third_byte.shr(6)))
             }
+            (Some(second_byte), None) => translate_char(0x3F_u8 & second_byte.shl(2)),
             (None, _) => '=',
         };
-        let fourth = third_byte.map_or('=', |byte| translate_char(0x3F as u8 & byte));
+        let fourth = third_byte.map_or('=', |byte| translate_char(0x3F_u8 & byte));
 
         result.push(first);
         result.push(second);
@@ -30,6 +30,62 @@ pub fn encode(bytes: Vec<u8>) -> String {
     result
 }
 
+/// Decodes standard base64, with the `+` and `/` alphabet and padding. Only the one canonical
+/// encoding of any input is accepted, so the padding has to be there and the bits it stands in
+/// for have to be zero.
+pub fn decode(input: &str) -> Result<Vec<u8>, DecodeError> {
+    let input = input.as_bytes();
+    if !input.len().is_multiple_of(4) {
+        return Err(DecodeError::InvalidLength);
+    }
+
+    let groups = input.len() / 4;
+    let mut bytes = Vec::with_capacity(groups * 3);
+    for (index, chunk) in input.chunks(4).enumerate() {
+        let padding = chunk.iter().rev().take_while(|&&byte| byte == b'=').count();
+        if padding > 2 || (padding > 0 && index != groups - 1) {
+            return Err(DecodeError::InvalidPadding);
+        }
+
+        let mut group = 0_u32;
+        for (position, &byte) in chunk[..4 - padding].iter().enumerate() {
+            let sextet = untranslate_char(byte).ok_or(DecodeError::InvalidCharacter(byte))?;
+            group |= (sextet as u32) << (18 - 6 * position);
+        }
+        let decoded = [(group >> 16) as u8, (group >> 8) as u8, group as u8];
+        let length = 3 - padding;
+        if decoded[length..].iter().any(|&byte| byte != 0) {
+            return Err(DecodeError::InvalidPadding);
+        }
+        bytes.extend_from_slice(&decoded[..length]);
+    }
+    Ok(bytes)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The input isn't made of whole groups of four characters
+    InvalidLength,
+    /// A byte that isn't in the base64 alphabet, or padding somewhere other than the end
+    InvalidCharacter(u8),
+    /// More than two padding characters, or padded bits that aren't zero
+    InvalidPadding,
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::InvalidLength => write!(f, "Base64 length was not a multiple of 4"),
+            DecodeError::InvalidCharacter(byte) => {
+                write!(f, "Invalid base64 character {:?}", *byte as char)
+            }
+            DecodeError::InvalidPadding => write!(f, "Invalid base64 padding"),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
 fn translate_char(input: u8) -> char {
     match input {
         0..=25 => (input + 65) as char,
@@ -41,6 +97,17 @@ fn translate_char(input: u8) -> char {
     }
 }
 
+fn untranslate_char(input: u8) -> Option<u8> {
+    match input {
+        b'A'..=b'Z' => Some(input - 65),
+        b'a'..=b'z' => Some(input - 97 + 26),
+        b'0'..=b'9' => Some(input + 4),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -71,4 +138,36 @@ mod tests {
 
         assert_eq!(encoded, expected);
     }
+
+    #[test]
+    fn decoding_works() {
+        let decoded = decode("TWFueSBoYW5kcyBtYWtlIGxpZ2h0IHdvcmsu").unwrap();
+
+        assert_eq!(decoded, b"Many hands make light work.");
+        assert_eq!(decode("bGlnaHQgd29yay4=").unwrap(), b"light work.");
+        assert_eq!(decode("bGlnaHQgd29yaw==").unwrap(), b"light work");
+        assert_eq!(decode("").unwrap(), b"");
+    }
+
+    #[test]
+    fn decoding_reverses_encoding() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        for length in 0..bytes.len() {
+            let encoded = encode(bytes[..length].to_vec());
+            assert_eq!(decode(&encoded).unwrap(), &bytes[..length]);
+        }
+    }
+
+    #[test]
+    fn malformed_input_is_rejected() {
+        assert_eq!(decode("bGlnaHQ"), Err(DecodeError::InvalidLength));
+        assert_eq!(decode("bGln aHQ="), Err(DecodeError::InvalidLength));
+        assert_eq!(decode("bGl-"), Err(DecodeError::InvalidCharacter(b'-')));
+        assert_eq!(decode("bG=n"), Err(DecodeError::InvalidCharacter(b'=')));
+        assert_eq!(decode("bGw=bGw="), Err(DecodeError::InvalidPadding));
+        assert_eq!(decode("b==="), Err(DecodeError::InvalidPadding));
+        // Padded bits that aren't zero
+        assert_eq!(decode("bGx="), Err(DecodeError::InvalidPadding));
+        assert_eq!(decode("bH=="), Err(DecodeError::InvalidPadding));
+    }
 }
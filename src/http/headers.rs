@@ -0,0 +1,141 @@
+use std::fmt::Display;
+
+/// The headers of a request or response, in the order they were added. Names are compared without
+/// regard to case, as HTTP requires, but keep the case they were added with. A name can have
+/// several values, either from repeating the header or from a comma-separated list in one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> HeaderMap {
+        HeaderMap::default()
+    }
+
+    /// Adds a value for `name`, after any it already has
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.entries.push((name.to_string(), value.to_string()));
+    }
+
+    /// Sets the only value of `name`, replacing any it already has
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    /// Removes every value of `name`
+    pub fn remove(&mut self, name: &str) {
+        self.entries
+            .retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+    }
+
+    /// The first value of `name`
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.get_all(name).next()
+    }
+
+    /// Every value of `name`, in order
+    pub fn get_all<'a>(&'a self, name: &str) -> impl Iterator<Item = &'a str> {
+        let name = name.to_string();
+        self.entries
+            .iter()
+            .filter(move |(existing, _)| existing.eq_ignore_ascii_case(&name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// The tokens listed in every value of `name`, as in `Connection: keep-alive, Upgrade`. Empty
+    /// list elements are skipped, as the list syntax allows.
+    pub fn tokens<'a>(&'a self, name: &str) -> impl Iterator<Item = &'a str> {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|token| !token.is_empty())
+    }
+
+    /// Whether `token` is one of the tokens listed for `name`, ignoring case
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.tokens(name)
+            .any(|listed| listed.eq_ignore_ascii_case(token))
+    }
+
+    /// Every header, in the order they were added
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Writes each header as a `Name: value` line ending in CRLF
+impl Display for HeaderMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (name, value) in self.iter() {
+            write!(f, "{name}: {value}\r\n")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_ignores_case() {
+        let mut headers = HeaderMap::new();
+        headers.append("Sec-WebSocket-Key", "abc");
+
+        assert_eq!(headers.get("sec-websocket-key"), Some("abc"));
+        assert_eq!(headers.get("SEC-WEBSOCKET-KEY"), Some("abc"));
+        assert!(!headers.contains("Sec-WebSocket-Version"));
+    }
+
+    #[test]
+    fn repeated_headers_are_kept_in_order() {
+        let mut headers = HeaderMap::new();
+        headers.append("Cookie", "a=1");
+        headers.append("Host", "example.com");
+        headers.append("cookie", "b=2");
+
+        assert_eq!(
+            headers.get_all("Cookie").collect::<Vec<_>>(),
+            ["a=1", "b=2"]
+        );
+        assert_eq!(
+            headers.to_string(),
+            "Cookie: a=1\r\nHost: example.com\r\ncookie: b=2\r\n"
+        );
+
+        headers.insert("COOKIE", "c=3");
+        assert_eq!(headers.get_all("Cookie").collect::<Vec<_>>(), ["c=3"]);
+        assert_eq!(headers.len(), 2);
+    }
+
+    #[test]
+    fn token_lists_are_split_across_values() {
+        let mut headers = HeaderMap::new();
+        headers.append("Connection", "keep-alive, Upgrade");
+        headers.append("connection", " ,close,,");
+
+        assert_eq!(
+            headers.tokens("Connection").collect::<Vec<_>>(),
+            ["keep-alive", "Upgrade", "close"]
+        );
+        assert!(headers.has_token("connection", "upgrade"));
+        assert!(!headers.has_token("Connection", "Upgrad"));
+        assert!(!headers.has_token("Upgrade", "websocket"));
+    }
+}
@@ -0,0 +1,290 @@
+use std::fmt::Display;
+use std::io::BufRead;
+use std::str::FromStr;
+
+use crate::server::ServerError;
+
+mod headers;
+mod parser;
+mod uri;
+
+pub use headers::HeaderMap;
+pub use parser::{HttpParseError, MAX_HEADER_BYTES, MAX_HEADER_COUNT, MAX_REQUEST_LINE_LENGTH};
+pub use uri::{percent_decode, Query, Uri};
+
+pub(crate) use parser::is_token;
+use parser::{parse_header, parse_request_line, read_line};
+
+#[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum HttpMethod {
+    GET,
+    POST,
+    PUT,
+    DELETE,
+    CONNECT,
+    OPTIONS,
+    TRACE,
+    PATCH,
+    HEAD,
+}
+
+impl FromStr for HttpMethod {
+    type Err = ServerError;
+
+    fn from_str(input: &str) -> Result<HttpMethod, Self::Err> {
+        match input {
+            "GET" => Ok(HttpMethod::GET),
+            "POST" => Ok(HttpMethod::POST),
+            "PUT" => Ok(HttpMethod::PUT),
+            "DELETE" => Ok(HttpMethod::DELETE),
+            "CONNECT" => Ok(HttpMethod::CONNECT),
+            "OPTIONS" => Ok(HttpMethod::OPTIONS),
+            "TRACE" => Ok(HttpMethod::TRACE),
+            "PATCH" => Ok(HttpMethod::PATCH),
+            "HEAD" => Ok(HttpMethod::HEAD),
+            _ => Err(ServerError::InvalidHttpMethod),
+        }
+    }
+}
+
+// Is there a way to automate this process? maybe there's a macro..
+impl Display for HttpMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            HttpMethod::GET => write!(f, "GET"),
+            HttpMethod::POST => write!(f, "POST"),
+            HttpMethod::PUT => write!(f, "PUT"),
+            HttpMethod::DELETE => write!(f, "DELETE"),
+            HttpMethod::CONNECT => write!(f, "CONNECT"),
+            HttpMethod::OPTIONS => write!(f, "OPTIONS"),
+            HttpMethod::TRACE => write!(f, "TRACE"),
+            HttpMethod::PATCH => write!(f, "PATCH"),
+            HttpMethod::HEAD => write!(f, "HEAD"),
+        }
+    }
+}
+
+/// The version of HTTP a request was made with. Versions compare by their major number and then
+/// their minor number, so HTTP/1.10 would come after HTTP/1.9.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HttpVersion {
+    pub major: u8,
+    pub minor: u8,
+}
+
+impl HttpVersion {
+    pub const HTTP_1_0: HttpVersion = HttpVersion { major: 1, minor: 0 };
+    pub const HTTP_1_1: HttpVersion = HttpVersion { major: 1, minor: 1 };
+}
+
+impl FromStr for HttpVersion {
+    type Err = ServerError;
+
+    /// Parses a version as it appears in a request line, like `HTTP/1.1`
+    fn from_str(input: &str) -> Result<HttpVersion, Self::Err> {
+        let number = |part: &str| match part.bytes().all(|byte| byte.is_ascii_digit()) {
+            true => part.parse().ok(),
+            false => None,
+        };
+        input
+            .strip_prefix("HTTP/")
+            .and_then(|version| version.split_once('.'))
+            .and_then(|(major, minor)| {
+                Some(HttpVersion {
+                    major: number(major)?,
+                    minor: number(minor)?,
+                })
+            })
+            .ok_or(HttpParseError::InvalidVersion.into())
+    }
+}
+
+impl Display for HttpVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HTTP/{}.{}", self.major, self.minor)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: HttpMethod,
+    pub uri: Uri,
+    pub http_version: HttpVersion,
+    pub headers: HeaderMap,
+}
+
+impl HttpRequest {
+    /// Parses the request from `reader`, reading no further than the end of the headers so that
+    /// anything after them stays in the reader's buffer. The request has to be well formed
+    /// HTTP/1.1 within the limits in this module.
+    pub fn build<R: BufRead>(reader: &mut R) -> Result<HttpRequest, ServerError> {
+        let mut line = read_line(
+            reader,
+            MAX_REQUEST_LINE_LENGTH,
+            HttpParseError::RequestLineTooLong,
+        )?;
+        // RFC 7230 asks servers to put up with a blank line ahead of the request line
+        if line.is_empty() {
+            line = read_line(
+                reader,
+                MAX_REQUEST_LINE_LENGTH,
+                HttpParseError::RequestLineTooLong,
+            )?;
+        }
+        let (method, uri, http_version) = parse_request_line(&line)?;
+
+        let mut request = HttpRequest {
+            method: HttpMethod::from_str(method)?,
+            uri: Uri::parse(uri)?,
+            http_version: HttpVersion::from_str(http_version)?,
+            headers: HeaderMap::new(),
+        };
+
+        let mut remaining = MAX_HEADER_BYTES - (line.len() + 2);
+        loop {
+            let line = read_line(reader, remaining, HttpParseError::HeadersTooLarge)?;
+            remaining -= line.len() + 2;
+            if line.is_empty() {
+                break;
+            }
+
+            if request.headers.len() == MAX_HEADER_COUNT {
+                return Err(HttpParseError::TooManyHeaders.into());
+            }
+            let (name, value) = parse_header(&line)?;
+            request.headers.append(name, value);
+        }
+
+        Ok(request)
+    }
+
+    /// The host the request was sent to according to its `Host` header, without the port. `None`
+    /// if there's no `Host` or it isn't a valid host. A target in absolute form isn't taken into
+    /// account, the server rejects handshakes whose target names a different host from the header.
+    pub fn host(&self) -> Option<&str> {
+        host_name(self.headers.get("Host")?)
+    }
+}
+
+/// The host in `authority`, without its port, if it's a valid host and port
+fn host_name(authority: &str) -> Option<&str> {
+    // A colon inside the brackets of an IPv6 address doesn't start the port
+    let end = match authority.rfind(':') {
+        Some(colon) if !authority[colon..].contains(']') => colon,
+        _ => authority.len(),
+    };
+    let (host, port) = authority.split_at(end);
+    if !port.bytes().skip(1).all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    let valid = match host.strip_prefix('[').and_then(|ip| ip.strip_suffix(']')) {
+        Some(ip) => {
+            !ip.is_empty()
+                && ip
+                    .bytes()
+                    .all(|byte| byte.is_ascii_hexdigit() || b":.".contains(&byte))
+        }
+        None => {
+            !host.is_empty()
+                && host
+                    .bytes()
+                    .all(|byte| byte.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=%".contains(&byte))
+        }
+    };
+    valid.then_some(host)
+}
+
+impl Display for HttpRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {}", self.method, self.uri, self.http_version)?;
+        for (key, value) in self.headers.iter() {
+            write!(f, "\n{key}: {value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Builds a response with the given status and headers. A non-empty `body` is sent as plain text,
+/// with the headers describing it added.
+pub(crate) fn build_http_response(
+    code: u16,
+    desc: &str,
+    mut headers: HeaderMap,
+    body: &str,
+) -> String {
+    if !body.is_empty() {
+        headers.insert("Content-Type", "text/plain; charset=utf-8");
+        headers.insert("Content-Length", &body.len().to_string());
+    }
+
+    format!("HTTP/1.1 {code} {desc}\r\n{headers}\r\n{body}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_parse_and_compare() {
+        let version = |input: &str| input.parse::<HttpVersion>().ok();
+
+        assert_eq!(version("HTTP/1.1"), Some(HttpVersion::HTTP_1_1));
+        assert_eq!(
+            version("HTTP/2.0"),
+            Some(HttpVersion { major: 2, minor: 0 })
+        );
+        assert_eq!(version("HTTP/1.1").unwrap().to_string(), "HTTP/1.1");
+        for malformed in [
+            "HTTP/1",
+            "HTTP/1.x",
+            "HTTP/+1.1",
+            "http/1.1",
+            "HTTP/1.1.1",
+            "1.1",
+        ] {
+            assert_eq!(version(malformed), None, "{malformed:?}");
+        }
+
+        assert!(HttpVersion::HTTP_1_0 < HttpVersion::HTTP_1_1);
+        assert!(
+            HttpVersion {
+                major: 1,
+                minor: 10
+            } > HttpVersion { major: 1, minor: 9 }
+        );
+        assert!(HttpVersion { major: 2, minor: 0 } > HttpVersion { major: 1, minor: 9 });
+    }
+
+    #[test]
+    fn hosts_are_taken_without_their_ports() {
+        for (authority, expected) in [
+            ("example.com", Some("example.com")),
+            ("Example.com:8080", Some("Example.com")),
+            ("127.0.0.1:80", Some("127.0.0.1")),
+            ("[::1]", Some("[::1]")),
+            ("[::1]:8080", Some("[::1]")),
+            ("example.com:", Some("example.com")),
+            ("", None),
+            (":80", None),
+            ("example.com:http", None),
+            ("example.com:80:80", None),
+            ("a b", None),
+            ("[]:80", None),
+        ] {
+            assert_eq!(host_name(authority), expected, "{authority:?}");
+        }
+    }
+
+    #[test]
+    fn the_host_header_names_the_host() {
+        let build = |request: &str| HttpRequest::build(&mut request.as_bytes()).unwrap();
+
+        let request = build("GET /chat HTTP/1.1\r\nHost: example.com:80\r\n\r\n");
+        assert_eq!(request.host(), Some("example.com"));
+        let request = build("GET ws://other.com/chat HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        assert_eq!(request.host(), Some("example.com"));
+        assert_eq!(build("GET /chat HTTP/1.1\r\n\r\n").host(), None);
+    }
+}
@@ -0,0 +1,304 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::io::{BufRead, ErrorKind};
+
+use crate::server::ServerError;
+
+/// The longest request line accepted, counting its CRLF
+pub const MAX_REQUEST_LINE_LENGTH: usize = 8 * 1024;
+/// The most header fields a request can have
+pub const MAX_HEADER_COUNT: usize = 100;
+/// The most bytes a request can take up to the end of its headers, counting the request line and
+/// every line ending
+pub const MAX_HEADER_BYTES: usize = 16 * 1024;
+
+/// Why a request couldn't be parsed as HTTP/1.1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpParseError {
+    RequestLineTooLong,
+    TooManyHeaders,
+    HeadersTooLarge,
+    /// The request line isn't a method, target and version separated by single spaces
+    MalformedRequestLine,
+    /// The version in the request line isn't of the form `HTTP/1.1`
+    InvalidVersion,
+    /// A header field isn't a token followed by a colon and a value of visible characters
+    MalformedHeader,
+    /// A header field continues onto the next line, which RFC 7230 no longer allows
+    ObsoleteLineFolding,
+    /// A line ends in a LF without the CR before it
+    BareLineFeed,
+    /// The client stopped sending before the end of the headers
+    Incomplete,
+}
+
+impl HttpParseError {
+    /// Whether this is the request being too big rather than badly formed
+    pub fn is_too_large(&self) -> bool {
+        matches!(
+            self,
+            HttpParseError::RequestLineTooLong
+                | HttpParseError::TooManyHeaders
+                | HttpParseError::HeadersTooLarge
+        )
+    }
+}
+
+impl Display for HttpParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpParseError::RequestLineTooLong => write!(
+                f,
+                "the request line is longer than {MAX_REQUEST_LINE_LENGTH} bytes"
+            ),
+            HttpParseError::TooManyHeaders => {
+                write!(f, "there are more than {MAX_HEADER_COUNT} header fields")
+            }
+            HttpParseError::HeadersTooLarge => {
+                write!(f, "the headers are longer than {MAX_HEADER_BYTES} bytes")
+            }
+            HttpParseError::MalformedRequestLine => write!(f, "the request line is malformed"),
+            HttpParseError::InvalidVersion => write!(f, "the HTTP version is malformed"),
+            HttpParseError::MalformedHeader => write!(f, "a header field is malformed"),
+            HttpParseError::ObsoleteLineFolding => {
+                write!(f, "header fields can't be folded onto several lines")
+            }
+            HttpParseError::BareLineFeed => write!(f, "lines must end in CRLF"),
+            HttpParseError::Incomplete => write!(f, "the request ended before its headers did"),
+        }
+    }
+}
+
+impl Error for HttpParseError {}
+
+impl From<HttpParseError> for ServerError {
+    fn from(error: HttpParseError) -> ServerError {
+        ServerError::HttpRequestParse(error)
+    }
+}
+
+/// Reads a line ending in CRLF and returns it without the CRLF. Nothing past the line is consumed
+/// from `reader`. If the line is longer than `limit`, counting the CRLF, `too_long` is returned
+/// once that many bytes have been read.
+pub(super) fn read_line<R: BufRead>(
+    reader: &mut R,
+    limit: usize,
+    too_long: HttpParseError,
+) -> Result<Vec<u8>, ServerError> {
+    let mut line = Vec::new();
+    loop {
+        let available = match reader.fill_buf() {
+            Ok([]) => return Err(HttpParseError::Incomplete.into()),
+            Ok(available) => available,
+            Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error.into()),
+        };
+        let (length, done) = match available.iter().position(|&byte| byte == b'\n') {
+            Some(end) => (end + 1, true),
+            None => (available.len(), false),
+        };
+        if line.len() + length > limit {
+            return Err(too_long.into());
+        }
+        line.extend_from_slice(&available[..length]);
+        reader.consume(length);
+        if done {
+            break;
+        }
+    }
+
+    line.pop();
+    match line.pop() {
+        Some(b'\r') => Ok(line),
+        _ => Err(HttpParseError::BareLineFeed.into()),
+    }
+}
+
+/// Splits a request line into its method, request target and version. Only their syntax is
+/// checked here.
+pub(super) fn parse_request_line(line: &[u8]) -> Result<(&str, &str, &str), HttpParseError> {
+    if !line
+        .iter()
+        .all(|byte| byte.is_ascii_graphic() || *byte == b' ')
+    {
+        return Err(HttpParseError::MalformedRequestLine);
+    }
+    // Checked to be ASCII just above
+    let line = std::str::from_utf8(line).map_err(|_| HttpParseError::MalformedRequestLine)?;
+
+    let mut parts = line.split(' ');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None)
+            if is_token(method) && !target.is_empty() && !version.is_empty() =>
+        {
+            Ok((method, target, version))
+        }
+        _ => Err(HttpParseError::MalformedRequestLine),
+    }
+}
+
+/// Splits a header line into its name and its value, without the optional whitespace around the
+/// value
+pub(super) fn parse_header(line: &[u8]) -> Result<(&str, &str), HttpParseError> {
+    if let Some(b' ' | b'\t') = line.first() {
+        return Err(HttpParseError::ObsoleteLineFolding);
+    }
+
+    let colon = line
+        .iter()
+        .position(|&byte| byte == b':')
+        .ok_or(HttpParseError::MalformedHeader)?;
+    let name = std::str::from_utf8(&line[..colon]).map_err(|_| HttpParseError::MalformedHeader)?;
+    if !is_token(name) {
+        return Err(HttpParseError::MalformedHeader);
+    }
+
+    let value = trim_whitespace(&line[colon + 1..]);
+    let is_value_byte = |byte: &u8| !byte.is_ascii_control() || *byte == b'\t';
+    if !value.iter().all(is_value_byte) {
+        return Err(HttpParseError::MalformedHeader);
+    }
+    let value = std::str::from_utf8(value).map_err(|_| HttpParseError::MalformedHeader)?;
+
+    Ok((name, value))
+}
+
+/// Whether `input` is a token, the syntax of methods, header names and many header values
+pub(crate) fn is_token(input: &str) -> bool {
+    !input.is_empty()
+        && input
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
+
+/// `input` without the spaces and tabs at either end
+fn trim_whitespace(mut input: &[u8]) -> &[u8] {
+    while let [b' ' | b'\t', rest @ ..] = input {
+        input = rest;
+    }
+    while let [rest @ .., b' ' | b'\t'] = input {
+        input = rest;
+    }
+    input
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http::HttpRequest;
+    use crate::server::ServerError;
+
+    use super::*;
+
+    fn parse(request: &str) -> Result<HttpRequest, HttpParseError> {
+        match HttpRequest::build(&mut request.as_bytes()) {
+            Ok(request) => Ok(request),
+            Err(ServerError::HttpRequestParse(error)) => Err(error),
+            Err(error) => panic!("unexpected error {error:?}"),
+        }
+    }
+
+    #[test]
+    fn optional_whitespace_is_trimmed() {
+        let request = parse(
+            "GET /chat HTTP/1.1\r\n\
+             Host:example.com\r\n\
+             Upgrade: \t websocket \t\r\n\
+             X-Empty:\r\n\r\n",
+        )
+        .unwrap();
+
+        assert_eq!(request.headers.get("Host"), Some("example.com"));
+        assert_eq!(request.headers.get("Upgrade"), Some("websocket"));
+        assert_eq!(request.headers.get("X-Empty"), Some(""));
+    }
+
+    #[test]
+    fn a_leading_blank_line_is_ignored() {
+        let request = parse("\r\nGET /chat HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+
+        assert_eq!(request.uri.path(), "/chat");
+    }
+
+    #[test]
+    fn nothing_after_the_headers_is_read() {
+        let mut input = &b"GET / HTTP/1.1\r\nHost: a\r\n\r\nframes"[..];
+        HttpRequest::build(&mut input).unwrap();
+
+        assert_eq!(input, b"frames");
+    }
+
+    #[test]
+    fn malformed_requests_are_rejected() {
+        for (request, expected) in [
+            (
+                "GET  /chat HTTP/1.1\r\n\r\n",
+                HttpParseError::MalformedRequestLine,
+            ),
+            ("GET /chat\r\n\r\n", HttpParseError::MalformedRequestLine),
+            (
+                "GET /chat HTTP/1.1 x\r\n\r\n",
+                HttpParseError::MalformedRequestLine,
+            ),
+            ("GET /chat HTTP/1.1\n\r\n", HttpParseError::BareLineFeed),
+            (
+                "GET /chat HTTP/1.1\r\nHost: a\n\r\n",
+                HttpParseError::BareLineFeed,
+            ),
+            (
+                "GET /chat HTTP/1.1\r\nHost : a\r\n\r\n",
+                HttpParseError::MalformedHeader,
+            ),
+            (
+                "GET /chat HTTP/1.1\r\nHost a\r\n\r\n",
+                HttpParseError::MalformedHeader,
+            ),
+            (
+                "GET /chat HTTP/1.1\r\n: a\r\n\r\n",
+                HttpParseError::MalformedHeader,
+            ),
+            (
+                "GET /chat HTTP/1.1\r\nX: a\rb\r\n\r\n",
+                HttpParseError::MalformedHeader,
+            ),
+            (
+                "GET /chat HTTP/1.1\r\nX: a\r\n b\r\n\r\n",
+                HttpParseError::ObsoleteLineFolding,
+            ),
+            (
+                "GET /chat HTTP/1.1\r\nHost: a\r\n",
+                HttpParseError::Incomplete,
+            ),
+            ("", HttpParseError::Incomplete),
+        ] {
+            assert_eq!(parse(request).unwrap_err(), expected, "{request:?}");
+        }
+    }
+
+    #[test]
+    fn limits_are_enforced() {
+        let long_target = format!(
+            "GET /{} HTTP/1.1\r\n\r\n",
+            "a".repeat(MAX_REQUEST_LINE_LENGTH)
+        );
+        assert_eq!(
+            parse(&long_target).unwrap_err(),
+            HttpParseError::RequestLineTooLong
+        );
+
+        let many_headers = format!(
+            "GET / HTTP/1.1\r\n{}\r\n",
+            "X: a\r\n".repeat(MAX_HEADER_COUNT + 1)
+        );
+        assert_eq!(
+            parse(&many_headers).unwrap_err(),
+            HttpParseError::TooManyHeaders
+        );
+
+        let long_header = format!("X: {}\r\n", "a".repeat(MAX_HEADER_BYTES / 4));
+        let large_headers = format!("GET / HTTP/1.1\r\n{}\r\n", long_header.repeat(4));
+        assert_eq!(
+            parse(&large_headers).unwrap_err(),
+            HttpParseError::HeadersTooLarge
+        );
+    }
+}
@@ -0,0 +1,290 @@
+use std::fmt::Display;
+
+use crate::server::ServerError;
+
+/// The target of a request, in either the origin form a request usually has (`/chat?room=1`) or
+/// the absolute form (`ws://example.com/chat?room=1`). Fragments aren't part of a request target,
+/// and the asterisk and authority forms can't be used to open a websocket, so all of those are
+/// rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Uri {
+    /// The target exactly as it appeared in the request line
+    target: String,
+    scheme: Option<String>,
+    authority: Option<String>,
+    path: String,
+    segments: Vec<String>,
+    query: Query,
+}
+
+/// The parameters in a query string, decoded and in the order they appeared. A key can appear
+/// more than once.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Query {
+    pairs: Vec<(String, String)>,
+}
+
+impl Uri {
+    pub fn parse(target: &str) -> Result<Uri, ServerError> {
+        if !target.bytes().all(is_uri_byte) {
+            return Err(ServerError::InvalidUri);
+        }
+
+        let (scheme, authority, rest) = match target.split_once("://") {
+            Some((scheme, rest)) if !target.starts_with('/') => {
+                if !is_scheme(scheme) {
+                    return Err(ServerError::InvalidUri);
+                }
+                let end = rest.find(['/', '?']).unwrap_or(rest.len());
+                let (authority, rest) = rest.split_at(end);
+                if authority.is_empty() || authority.contains('@') {
+                    return Err(ServerError::InvalidUri);
+                }
+                (Some(scheme), Some(authority), rest)
+            }
+            _ if target.starts_with('/') => (None, None, target),
+            _ => return Err(ServerError::InvalidUri),
+        };
+
+        let (path, query) = match rest.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (rest, None),
+        };
+        // An absolute form target can leave out the path
+        let path = match path {
+            "" => "/",
+            path => path,
+        };
+
+        let segments = path[1..]
+            .split('/')
+            .map(percent_decode)
+            .collect::<Result<_, _>>()?;
+        let query = match query {
+            Some(query) => Query::parse(query)?,
+            None => Query::default(),
+        };
+
+        Ok(Uri {
+            target: target.to_string(),
+            scheme: scheme.map(str::to_ascii_lowercase),
+            authority: authority.map(str::to_string),
+            path: path.to_string(),
+            segments,
+            query,
+        })
+    }
+
+    /// The scheme of an absolute form target, in lowercase
+    pub fn scheme(&self) -> Option<&str> {
+        self.scheme.as_deref()
+    }
+
+    /// The host and port of an absolute form target
+    pub fn authority(&self) -> Option<&str> {
+        self.authority.as_deref()
+    }
+
+    /// The path as it was sent, still percent-encoded so that an encoded `/` can be told apart
+    /// from the one between segments
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The decoded segments of the path, not counting its leading `/`. The path `/` has a single
+    /// empty segment.
+    pub fn segments(&self) -> &[String] {
+        &self.segments
+    }
+
+    pub fn query(&self) -> &Query {
+        &self.query
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.target
+    }
+
+    /// This target with the first `count` segments of its path taken off, which is what a handler
+    /// mounted under a prefix routes on. The query and the target as it was sent are kept.
+    pub(crate) fn strip_segments(&self, count: usize) -> Uri {
+        let count = count.min(self.segments.len());
+        let rest: Vec<&str> = self.path[1..].split('/').skip(count).collect();
+        let (path, segments) = if rest.is_empty() {
+            ("/".to_string(), vec![String::new()])
+        } else {
+            (
+                format!("/{}", rest.join("/")),
+                self.segments[count..].to_vec(),
+            )
+        };
+        Uri {
+            path,
+            segments,
+            ..self.clone()
+        }
+    }
+}
+
+impl Display for Uri {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.target)
+    }
+}
+
+impl Query {
+    /// Parses an `application/x-www-form-urlencoded` query string, where a `+` stands for a space.
+    /// A parameter without an `=` has an empty value.
+    pub fn parse(query: &str) -> Result<Query, ServerError> {
+        let pairs = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                let decode = |part: &str| percent_decode(&part.replace('+', " "));
+                Ok((decode(key)?, decode(value)?))
+            })
+            .collect::<Result<_, ServerError>>()?;
+        Ok(Query { pairs })
+    }
+
+    /// The first value given for `key`
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.get_all(key).next()
+    }
+
+    /// Every value given for `key`, in order
+    pub fn get_all<'a>(&'a self, key: &str) -> impl Iterator<Item = &'a str> {
+        let key = key.to_string();
+        self.pairs
+            .iter()
+            .filter(move |(name, _)| *name == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.pairs
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+}
+
+/// Decodes the `%XX` escapes in `input`. Every `%` has to start an escape, and the decoded bytes
+/// have to be UTF-8.
+pub fn percent_decode(input: &str) -> Result<String, ServerError> {
+    let mut bytes = Vec::with_capacity(input.len());
+    let mut rest = input.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        if byte != b'%' {
+            bytes.push(byte);
+            continue;
+        }
+        match rest {
+            [high, low, tail @ ..] => {
+                let high = (*high as char)
+                    .to_digit(16)
+                    .ok_or(ServerError::InvalidUri)?;
+                let low = (*low as char).to_digit(16).ok_or(ServerError::InvalidUri)?;
+                bytes.push((high << 4 | low) as u8);
+                rest = tail;
+            }
+            _ => return Err(ServerError::InvalidUri),
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| ServerError::InvalidUri)
+}
+
+/// Whether `byte` can appear in a request target, from RFC 3986. `#` can't, since the fragment
+/// is never sent.
+fn is_uri_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@/?%[]".contains(&byte)
+}
+
+fn is_scheme(scheme: &str) -> bool {
+    let mut bytes = scheme.bytes();
+    bytes.next().is_some_and(|byte| byte.is_ascii_alphabetic())
+        && bytes.all(|byte| byte.is_ascii_alphanumeric() || b"+-.".contains(&byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn origin_form_works() {
+        let uri = Uri::parse("/rooms/a%2Fb/users?x=1").unwrap();
+
+        assert_eq!(uri.scheme(), None);
+        assert_eq!(uri.path(), "/rooms/a%2Fb/users");
+        assert_eq!(uri.segments(), ["rooms", "a/b", "users"]);
+        assert_eq!(uri.query().get("x"), Some("1"));
+        assert_eq!(Uri::parse("/").unwrap().segments(), [""]);
+    }
+
+    #[test]
+    fn absolute_form_works() {
+        let uri = Uri::parse("WS://example.com:8080?token=abc").unwrap();
+
+        assert_eq!(uri.scheme(), Some("ws"));
+        assert_eq!(uri.authority(), Some("example.com:8080"));
+        assert_eq!(uri.path(), "/");
+        assert_eq!(uri.query().get("token"), Some("abc"));
+        assert_eq!(uri.to_string(), "WS://example.com:8080?token=abc");
+    }
+
+    #[test]
+    fn stripping_segments_keeps_the_rest() {
+        let uri = Uri::parse("/api/rooms/a%2Fb?x=1").unwrap();
+
+        let rest = uri.strip_segments(1);
+        assert_eq!(rest.path(), "/rooms/a%2Fb");
+        assert_eq!(rest.segments(), ["rooms", "a/b"]);
+        assert_eq!(rest.query().get("x"), Some("1"));
+        assert_eq!(rest.as_str(), uri.as_str());
+
+        let rest = uri.strip_segments(3);
+        assert_eq!(rest.path(), "/");
+        assert_eq!(rest.segments(), [""]);
+    }
+
+    #[test]
+    fn query_keeps_repeated_keys_in_order() {
+        let query = Query::parse("tag=a&name=J%C3%BCrgen+Z&tag=b&flag&&tag=").unwrap();
+
+        assert_eq!(query.get_all("tag").collect::<Vec<_>>(), ["a", "b", ""]);
+        assert_eq!(query.get("name"), Some("Jürgen Z"));
+        assert_eq!(query.get("flag"), Some(""));
+        assert_eq!(query.get("missing"), None);
+        assert_eq!(query.iter().count(), 5);
+    }
+
+    #[test]
+    fn malformed_targets_are_rejected() {
+        for target in [
+            "",
+            "*",
+            "chat",
+            "example.com:80",
+            "/chat#top",
+            "/a b",
+            "/caf\u{e9}",
+            "/%",
+            "/%4",
+            "/%zz",
+            "/%C3",
+            "1ws://example.com/",
+            "ws:///chat",
+            "ws://user@example.com/",
+        ] {
+            assert!(
+                matches!(Uri::parse(target), Err(ServerError::InvalidUri)),
+                "{target:?} should be rejected"
+            );
+        }
+    }
+}
@@ -0,0 +1,5 @@
+pub mod base64;
+pub mod http;
+pub mod server;
+pub mod sha1;
+pub mod websocket;
@@ -0,0 +1,90 @@
+use std::io::{self, ErrorKind};
+use std::time::Duration;
+
+const MIN_DELAY: Duration = Duration::from_millis(5);
+const MAX_DELAY: Duration = Duration::from_secs(1);
+
+/// Decides what to do after accepting a connection fails.
+///
+/// Most failures belong to the one connection being accepted and the next can be accepted straight
+/// away. Others mean the process has run short of something, like EMFILE when it's out of file
+/// descriptors, and retrying straight away would only spin until some connection closes. Those back
+/// off, doubling the delay each time up to a second. Neither is a reason to stop serving.
+#[derive(Debug)]
+pub(crate) struct AcceptBackoff {
+    delay: Duration,
+}
+
+impl AcceptBackoff {
+    pub fn new() -> AcceptBackoff {
+        AcceptBackoff { delay: MIN_DELAY }
+    }
+
+    /// Logs the error and returns how long to wait before accepting again. The error is given
+    /// back if the listener itself can't be used, since retrying would never succeed.
+    pub fn failed(&mut self, error: io::Error) -> Result<Duration, io::Error> {
+        match error.kind() {
+            ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionReset
+            | ErrorKind::Interrupted
+            | ErrorKind::TimedOut
+            | ErrorKind::PermissionDenied => {
+                eprintln!("Error accepting a connection: {error}");
+                Ok(Duration::ZERO)
+            }
+            // The socket isn't listening
+            ErrorKind::InvalidInput => Err(error),
+            _ => {
+                let delay = self.delay;
+                self.delay = (delay * 2).min(MAX_DELAY);
+                eprintln!("Error accepting a connection, retrying in {delay:?}: {error}");
+                Ok(delay)
+            }
+        }
+    }
+
+    /// Resets the delay once connections are being accepted again
+    pub fn succeeded(&mut self) {
+        self.delay = MIN_DELAY;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // EMFILE on Linux and the BSDs
+    const TOO_MANY_OPEN_FILES: i32 = 24;
+
+    #[test]
+    fn running_out_of_file_descriptors_backs_off() {
+        let mut backoff = AcceptBackoff::new();
+        let mut failed = || {
+            backoff
+                .failed(io::Error::from_raw_os_error(TOO_MANY_OPEN_FILES))
+                .unwrap()
+        };
+
+        assert_eq!(failed(), MIN_DELAY);
+        assert_eq!(failed(), MIN_DELAY * 2);
+        for _ in 0..20 {
+            failed();
+        }
+        assert_eq!(failed(), MAX_DELAY);
+
+        backoff.succeeded();
+        assert_eq!(
+            backoff.failed(ErrorKind::OutOfMemory.into()).unwrap(),
+            MIN_DELAY
+        );
+    }
+
+    #[test]
+    fn failures_of_one_connection_are_retried_immediately() {
+        let mut backoff = AcceptBackoff::new();
+        let delay = backoff.failed(ErrorKind::ConnectionAborted.into());
+
+        assert_eq!(delay.unwrap(), Duration::ZERO);
+        assert!(backoff.failed(ErrorKind::InvalidInput.into()).is_err());
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+
+use crate::http::{HttpRequest, Query};
+use crate::websocket::{CloseCode, Message, Shutdown, WebSocket, WebSocketError};
+
+/// Anything a connection can be served over, boxed so that [`Connection`] is the same type
+/// however the server drives its sockets
+pub(crate) trait Stream: Read + Write + Shutdown + Send + Debug {}
+
+impl<S: Read + Write + Shutdown + Send + Debug> Stream for S {}
+
+/// An open websocket, as handed to a [`Handler`](super::Handler)
+#[derive(Debug)]
+pub struct Connection {
+    websocket: WebSocket<Box<dyn Stream>>,
+    peer_addr: SocketAddr,
+    params: HashMap<String, String>,
+    query: Query,
+    origin: Option<String>,
+    protocol: Option<String>,
+}
+
+impl Connection {
+    pub(crate) fn new(
+        websocket: WebSocket<Box<dyn Stream>>,
+        peer_addr: SocketAddr,
+        request: &HttpRequest,
+        params: HashMap<String, String>,
+        protocol: Option<String>,
+    ) -> Connection {
+        Connection {
+            websocket,
+            peer_addr,
+            params,
+            query: request.uri.query().clone(),
+            origin: request.headers.get("Origin").map(str::to_string),
+            protocol,
+        }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// The value of a parameter in the route the connection was opened on, such as `id` for
+    /// `/rooms/:id`
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str)
+    }
+
+    pub fn params(&self) -> &HashMap<String, String> {
+        &self.params
+    }
+
+    /// The decoded query parameters of the handshake's URI
+    pub fn query(&self) -> &Query {
+        &self.query
+    }
+
+    /// The origin the handshake came from, which the server's
+    /// [`OriginPolicy`](super::OriginPolicy) has allowed. Clients other than browsers usually
+    /// don't send one.
+    pub fn origin(&self) -> Option<&str> {
+        self.origin.as_deref()
+    }
+
+    /// The subprotocol agreed on in the handshake, one of the server's
+    /// [`subprotocols`](super::ServerConfig::subprotocols)
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    pub fn send_text(&mut self, text: &str) -> Result<(), WebSocketError> {
+        self.websocket.send_text(text)
+    }
+
+    pub fn send_binary(&mut self, bytes: &[u8]) -> Result<(), WebSocketError> {
+        self.websocket.send_binary(bytes)
+    }
+
+    pub fn send_ping(&mut self, payload: &[u8]) -> Result<(), WebSocketError> {
+        self.websocket.send_ping(payload)
+    }
+
+    /// Starts the closing handshake. The connection ends, calling
+    /// [`on_close`](super::Handler::on_close), once the client replies with its own close, or
+    /// with [`CloseCode::Abnormal`] if it hasn't replied within the config's `close_timeout`.
+    pub fn close(&mut self, code: CloseCode, reason: &str) -> Result<(), WebSocketError> {
+        self.websocket.send_close(code, reason)
+    }
+
+    pub(crate) fn read_message(&mut self) -> Result<Message, WebSocketError> {
+        self.websocket.read_message()
+    }
+
+    /// Writes out anything the stream is holding on to
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        self.websocket.get_mut().flush()
+    }
+}
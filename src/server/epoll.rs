@@ -0,0 +1,138 @@
+//! A minimal binding to Linux's epoll, just enough for the event loop. The crate has no
+//! dependencies, so the few functions needed are declared here rather than pulled in from libc.
+
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::raw::c_int;
+use std::time::Duration;
+
+const EPOLL_CLOEXEC: c_int = 0o2000000;
+const EPOLL_CTL_ADD: c_int = 1;
+
+pub const EPOLLIN: u32 = 0x001;
+pub const EPOLLOUT: u32 = 0x004;
+const EPOLLERR: u32 = 0x008;
+const EPOLLHUP: u32 = 0x010;
+pub const EPOLLRDHUP: u32 = 0x2000;
+pub const EPOLLET: u32 = 1 << 31;
+
+/// `struct epoll_event`, which the kernel packs on x86_64 only
+#[cfg_attr(target_arch = "x86_64", repr(C, packed))]
+#[cfg_attr(not(target_arch = "x86_64"), repr(C))]
+#[derive(Clone, Copy)]
+pub struct Event {
+    events: u32,
+    data: u64,
+}
+
+impl Event {
+    /// Whether there's something to read, which includes the peer hanging up or an error that a
+    /// read would report
+    pub fn is_readable(&self) -> bool {
+        self.events & (EPOLLIN | EPOLLRDHUP | EPOLLHUP | EPOLLERR) != 0
+    }
+
+    /// The token the file descriptor was registered with
+    pub fn token(&self) -> u64 {
+        self.data
+    }
+}
+
+extern "C" {
+    fn epoll_create1(flags: c_int) -> c_int;
+    fn epoll_ctl(epfd: c_int, op: c_int, fd: c_int, event: *mut Event) -> c_int;
+    fn epoll_wait(epfd: c_int, events: *mut Event, maxevents: c_int, timeout: c_int) -> c_int;
+}
+
+/// An epoll instance. File descriptors are removed from it by the kernel when they're closed, so
+/// there's nothing to deregister.
+#[derive(Debug)]
+pub struct Poller {
+    fd: OwnedFd,
+}
+
+impl Poller {
+    pub fn new() -> io::Result<Poller> {
+        let fd = check(unsafe { epoll_create1(EPOLL_CLOEXEC) })?;
+        // SAFETY: epoll_create1 returned a new descriptor that nothing else owns
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        Ok(Poller { fd })
+    }
+
+    /// Starts watching `source` for the events in `interest`, reporting them with `token`
+    pub fn add(&self, source: &impl AsRawFd, token: u64, interest: u32) -> io::Result<()> {
+        let mut event = Event {
+            events: interest,
+            data: token,
+        };
+        check(unsafe {
+            epoll_ctl(
+                self.fd.as_raw_fd(),
+                EPOLL_CTL_ADD,
+                source.as_raw_fd(),
+                &mut event,
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Waits for events, at most `timeout` if it's given, replacing the contents of `events` with
+    /// them. Waiting is interrupted by signals, in which case `events` is left empty.
+    pub fn wait(&self, events: &mut Vec<Event>, timeout: Option<Duration>) -> io::Result<()> {
+        let timeout = match timeout {
+            Some(timeout) => timeout.as_millis().min(c_int::MAX as u128) as c_int,
+            None => -1,
+        };
+
+        events.clear();
+        let capacity = events.capacity().min(c_int::MAX as usize) as c_int;
+        let result =
+            unsafe { epoll_wait(self.fd.as_raw_fd(), events.as_mut_ptr(), capacity, timeout) };
+        match check(result) {
+            // SAFETY: the kernel initialised this many events, which is no more than the capacity
+            Ok(count) => unsafe { events.set_len(count as usize) },
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+        Ok(())
+    }
+}
+
+fn check(result: c_int) -> io::Result<RawFd> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn readable_socket_is_reported() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        let poller = Poller::new().unwrap();
+        poller.add(&server, 7, EPOLLIN).unwrap();
+        let mut events = Vec::with_capacity(4);
+
+        poller
+            .wait(&mut events, Some(Duration::from_millis(10)))
+            .unwrap();
+        assert!(events.is_empty());
+
+        client.write_all(b"hi").unwrap();
+        poller
+            .wait(&mut events, Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].token(), 7);
+        assert!(events[0].is_readable());
+    }
+}
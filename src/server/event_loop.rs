@@ -0,0 +1,464 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant};
+
+use super::epoll::{Poller, EPOLLET, EPOLLIN, EPOLLOUT, EPOLLRDHUP};
+use super::{
+    at_capacity_response, dispatch, failed_close, read_handshake, AcceptBackoff, Connection,
+    Handler, ServerConfig, ServerError, Stream,
+};
+use crate::http::{HttpParseError, MAX_HEADER_BYTES};
+use crate::websocket::{Shutdown, WebSocket};
+
+/// The token the listener is registered with, connections count up from 0
+const LISTENER: u64 = u64::MAX;
+
+/// How often every connection is serviced whether or not anything happened on it, which is what
+/// keeps the heartbeats of idle connections running
+const TICK: Duration = Duration::from_secs(1);
+
+/// How much can be waiting to be written to a connection before writing more to it fails, which
+/// ends connections whose client has stopped reading
+const MAX_PENDING_BYTES: usize = 4 << 20;
+
+/// How long the response to a rejected handshake is given to be written out
+const REJECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves every connection from a single thread, handing them to `H`. Sockets are nonblocking and
+/// epoll reports which of them are ready, so idle connections cost no more than their buffers.
+///
+/// Handlers are called on the event loop's thread, so one that blocks holds up every connection.
+#[derive(Debug)]
+pub struct EventLoop<H> {
+    handler: H,
+    config: ServerConfig,
+}
+
+impl<H: Handler> EventLoop<H> {
+    pub fn new(handler: H) -> EventLoop<H> {
+        EventLoop::with_config(handler, ServerConfig::default())
+    }
+
+    pub fn with_config(handler: H, config: ServerConfig) -> EventLoop<H> {
+        EventLoop { handler, config }
+    }
+
+    /// Binds to `addr` and serves connections on it, see [`serve`](EventLoop::serve)
+    pub fn listen<A: ToSocketAddrs>(&self, addr: A) -> Result<(), ServerError> {
+        self.serve(TcpListener::bind(addr)?)
+    }
+
+    /// Serves connections from an already bound listener. As with [`Server`](super::Server),
+    /// anything that goes wrong with a connection only ends that connection, and failing to
+    /// accept one is retried. This only returns if the listener or epoll can't be used.
+    pub fn serve(&self, listener: TcpListener) -> Result<(), ServerError> {
+        listener.set_nonblocking(true)?;
+        let poller = Poller::new()?;
+        poller.add(&listener, LISTENER, EPOLLIN | EPOLLET)?;
+
+        let mut clients: HashMap<u64, Client> = HashMap::new();
+        let mut next_token = 0;
+        let mut events = Vec::with_capacity(1024);
+        let mut last_tick = Instant::now();
+        let mut backoff = AcceptBackoff::new();
+        // Set while backing off after failing to accept, the listener is left alone until then
+        // and new connections wait in its backlog
+        let mut accept_at: Option<Instant> = None;
+
+        loop {
+            let timeout = match accept_at {
+                Some(at) => at.saturating_duration_since(Instant::now()).min(TICK),
+                None => TICK,
+            };
+            poller.wait(&mut events, Some(timeout))?;
+
+            let mut accept = accept_at.is_some_and(|at| at <= Instant::now());
+            for event in &events {
+                if event.token() == LISTENER {
+                    accept |= accept_at.is_none();
+                } else {
+                    self.service(&mut clients, event.token(), event.is_readable());
+                }
+            }
+
+            // Edge triggered, so everything waiting has to be accepted now
+            if accept {
+                accept_at = None;
+                loop {
+                    let (mut stream, addr) = match listener.accept() {
+                        Ok(accepted) => {
+                            backoff.succeeded();
+                            accepted
+                        }
+                        Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                        Err(error) => {
+                            let delay = backoff.failed(error)?;
+                            if !delay.is_zero() {
+                                accept_at = Some(Instant::now() + delay);
+                                break;
+                            }
+                            continue;
+                        }
+                    };
+
+                    if clients.len() >= self.config.max_connections {
+                        let _ = stream.write_all(at_capacity_response().as_bytes());
+                        continue;
+                    }
+
+                    let token = next_token;
+                    next_token += 1;
+                    let registered = stream.set_nonblocking(true).and_then(|_| {
+                        poller.add(&stream, token, EPOLLIN | EPOLLOUT | EPOLLRDHUP | EPOLLET)
+                    });
+                    match registered {
+                        Ok(()) => {
+                            clients.insert(token, Client::new(stream, addr));
+                        }
+                        Err(error) => eprintln!("{error}"),
+                    }
+                }
+            }
+
+            if last_tick.elapsed() >= TICK {
+                let tokens: Vec<u64> = clients.keys().copied().collect();
+                for token in tokens {
+                    self.service(&mut clients, token, true);
+                }
+                last_tick = Instant::now();
+            }
+        }
+    }
+}
+
+impl<H: Handler> EventLoop<H> {
+    /// Services the client with `token`, dropping it once it's finished. Errors and panics only
+    /// drop the one client.
+    fn service<'a>(&'a self, clients: &mut HashMap<u64, Client<'a>>, token: u64, readable: bool) {
+        let client = match clients.remove(&token) {
+            Some(client) => client,
+            None => return,
+        };
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            client.service(&self.handler, &self.config, readable)
+        }));
+        match result {
+            Ok(Some(client)) => {
+                clients.insert(token, client);
+            }
+            Ok(None) => {}
+            Err(_) => eprintln!("A connection panicked and has been closed"),
+        }
+    }
+}
+
+enum Client<'a> {
+    /// Waiting for the rest of the client's opening handshake
+    Handshake {
+        stream: NonBlockingStream,
+        addr: SocketAddr,
+        read: Vec<u8>,
+        /// How much of `read` has been searched for the end of the headers
+        scanned: usize,
+        /// When the connection was accepted, which the handshake timeout counts from
+        accepted: Instant,
+    },
+    /// Writing out the response to a rejected handshake, after which the client is dropped
+    Rejected {
+        stream: NonBlockingStream,
+        /// When to give up on writing the response
+        until: Instant,
+    },
+    Open {
+        /// Boxed since it's several times the size of a handshake
+        connection: Box<Connection>,
+        /// The handler the connection was routed to
+        handler: &'a dyn Handler,
+    },
+}
+
+impl<'a> Client<'a> {
+    fn new(stream: TcpStream, addr: SocketAddr) -> Client<'a> {
+        Client::Handshake {
+            stream: NonBlockingStream::new(stream),
+            addr,
+            read: Vec::new(),
+            scanned: 0,
+            accepted: Instant::now(),
+        }
+    }
+
+    /// Reads and handles everything the client has sent, if `readable`, then writes out as much
+    /// as the socket will take. Returns `None` once the client is finished with.
+    ///
+    /// Errors are reported to the handler the connection was routed to, or to `handler` itself if
+    /// it never got that far.
+    fn service<H: Handler>(
+        self,
+        handler: &'a H,
+        config: &ServerConfig,
+        readable: bool,
+    ) -> Option<Client<'a>> {
+        match self {
+            Client::Handshake {
+                mut stream,
+                addr,
+                mut read,
+                mut scanned,
+                accepted,
+            } => {
+                // Reading stops just past the limit, so that a client sending endless headers is
+                // rejected without buffering any more of them
+                let result = match readable {
+                    true => stream.read_available(&mut read, MAX_HEADER_BYTES),
+                    false => Ok(true),
+                };
+                match result {
+                    Ok(true) => {}
+                    Ok(false) => return None,
+                    Err(error) => {
+                        handler.on_error(addr, &error.into());
+                        return None;
+                    }
+                }
+
+                // The end of the headers may have started in what was searched before
+                let from = scanned.saturating_sub(3);
+                let complete = read[from..].windows(4).any(|window| window == b"\r\n\r\n");
+                scanned = read.len();
+                if !complete {
+                    if read.len() > MAX_HEADER_BYTES {
+                        let error = HttpParseError::HeadersTooLarge.into();
+                        handler.on_error(addr, &error);
+                        return reject(stream, &error)?.service(handler, config, false);
+                    }
+                    // Checked on every tick, so idle clients can't hold on to connection slots
+                    if accepted.elapsed() >= config.handshake_timeout {
+                        handler.on_error(addr, &io::Error::from(ErrorKind::TimedOut).into());
+                        return None;
+                    }
+                    return Some(Client::Handshake {
+                        stream,
+                        addr,
+                        read,
+                        scanned,
+                        accepted,
+                    });
+                }
+
+                // Whatever follows the headers is the start of the first frames
+                let mut rest = &read[..];
+                let handshake = match read_handshake(&mut rest, handler, config) {
+                    Ok(handshake) => handshake,
+                    Err(error) => {
+                        handler.on_error(addr, &error);
+                        return reject(stream, &error)?.service(handler, config, false);
+                    }
+                };
+                stream
+                    .pending
+                    .extend_from_slice(handshake.response.as_bytes());
+
+                let stream: Box<dyn Stream> = Box::new(stream);
+                let mut websocket = WebSocket::from_partially_read(stream, rest, config.websocket);
+                websocket.set_extensions(handshake.extensions);
+                let mut connection = Connection::new(
+                    websocket,
+                    addr,
+                    &handshake.request,
+                    handshake.params,
+                    handshake.protocol,
+                );
+                handshake
+                    .handler
+                    .on_open(&mut connection, &handshake.request);
+                let client = Client::Open {
+                    connection: Box::new(connection),
+                    handler: handshake.handler,
+                };
+                client.service(handler, config, true)
+            }
+            Client::Rejected { mut stream, until } => {
+                let written = match stream.write_pending() {
+                    Ok(()) => stream.pending.is_empty(),
+                    Err(_) => true,
+                };
+                (!written && Instant::now() < until).then_some(Client::Rejected { stream, until })
+            }
+            Client::Open {
+                mut connection,
+                handler,
+            } => {
+                let mut open = true;
+                let mut result = Ok(());
+                while readable && open {
+                    match connection.read_message() {
+                        Ok(message) => open = dispatch(handler, &mut connection, message),
+                        Err(error) if error.is_timeout() => break,
+                        Err(error) => {
+                            result = Err(error.into());
+                            break;
+                        }
+                    }
+                }
+
+                // A close frame may still be waiting to go out
+                match result.and_then(|()| Ok(connection.flush()?)) {
+                    Ok(()) => open.then_some(Client::Open {
+                        connection,
+                        handler,
+                    }),
+                    Err(error) => {
+                        let addr = connection.peer_addr();
+                        if open {
+                            handler.on_close(addr, failed_close(&error));
+                        }
+                        handler.on_error(addr, &error);
+                        None
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Starts sending the response explaining why a handshake was rejected. The client is kept until
+/// all of it has been written, or [`REJECTION_TIMEOUT`] has passed, so that a response the socket
+/// won't take at once still arrives in full. `None` if there's no response to send.
+fn reject<'a>(mut stream: NonBlockingStream, error: &ServerError) -> Option<Client<'a>> {
+    let response = error.response()?;
+    stream.pending.extend_from_slice(response.as_bytes());
+    // The client is done with once the response is out
+    stream.shutdown = true;
+    Some(Client::Rejected {
+        stream,
+        until: Instant::now() + REJECTION_TIMEOUT,
+    })
+}
+
+/// A nonblocking socket whose writes never fail with `WouldBlock`. Whatever the socket won't take
+/// yet is kept and written once it's writable again, so a frame is never left half written. Once
+/// more than [`MAX_PENDING_BYTES`] is kept, writes and flushes fail instead.
+#[derive(Debug)]
+struct NonBlockingStream {
+    stream: TcpStream,
+    pending: Vec<u8>,
+    /// Set once the writing half should be shut down, which waits for `pending` to be written
+    shutdown: bool,
+}
+
+impl NonBlockingStream {
+    fn new(stream: TcpStream) -> NonBlockingStream {
+        NonBlockingStream {
+            stream,
+            pending: Vec::new(),
+            shutdown: false,
+        }
+    }
+
+    /// Reads everything that has arrived onto the end of `read`, but stops once `read` holds more
+    /// than `limit` bytes. Returns false if the peer has closed its end.
+    fn read_available(&mut self, read: &mut Vec<u8>, limit: usize) -> io::Result<bool> {
+        let mut chunk = [0; 4096];
+        while read.len() <= limit {
+            let wanted = chunk.len().min(limit + 1 - read.len());
+            match self.stream.read(&mut chunk[..wanted]) {
+                Ok(0) => return Ok(false),
+                Ok(length) => read.extend_from_slice(&chunk[..length]),
+                Err(error) if error.kind() == ErrorKind::WouldBlock => return Ok(true),
+                Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Writes as much of what's pending as the socket will take right now, shutting down the
+    /// writing half if that's been asked for and everything has been written
+    fn write_pending(&mut self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            match self.stream.write(&self.pending) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(length) => {
+                    self.pending.drain(..length);
+                }
+                Err(error) if error.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error),
+            }
+        }
+        if self.shutdown {
+            self.shutdown = false;
+            self.stream.shutdown_write()?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for NonBlockingStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl NonBlockingStream {
+    /// Fails if too much is still pending once the socket has taken what it will
+    fn check_pending(&mut self) -> io::Result<()> {
+        if self.pending.len() >= MAX_PENDING_BYTES {
+            self.write_pending()?;
+            if self.pending.len() >= MAX_PENDING_BYTES {
+                return Err(io::Error::other(
+                    "the client isn't reading what's sent to it",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Shutdown for NonBlockingStream {
+    /// Shuts down the writing half once everything pending has been written
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.shutdown = true;
+        self.write_pending()
+    }
+}
+
+impl Write for NonBlockingStream {
+    /// Always takes all of `buf` or none of it, so frames are never split
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_pending()?;
+        self.pending.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    /// Starts writing out what's pending, without waiting for the socket to take all of it
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_pending()?;
+        self.check_pending()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reading_stops_just_past_the_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        client.write_all(&[b'a'; 64 * 1024]).unwrap();
+        // Let everything arrive, so that only the limit stops the read
+        std::thread::sleep(Duration::from_millis(50));
+        server.set_nonblocking(true).unwrap();
+
+        let mut stream = NonBlockingStream::new(server);
+        let mut read = Vec::new();
+        assert!(stream.read_available(&mut read, 1000).unwrap());
+        assert_eq!(read.len(), 1001);
+    }
+}
@@ -0,0 +1,202 @@
+use super::ServerError;
+use crate::http::{is_token, HttpRequest};
+use crate::websocket::{Extension, ExtensionFactory, ExtensionParam, RsvBits};
+
+/// The extensions negotiated in a handshake, and the `Sec-WebSocket-Extensions` value accepting
+/// them if there are any
+pub(super) type Negotiated = (Vec<Box<dyn Extension>>, Option<String>);
+
+/// Negotiates the extensions the client offered that one of `factories` makes. Offers are
+/// considered in the order the client made them, and one is accepted if the extension agrees to
+/// its parameters and doesn't need reserved bits an extension accepted before it already has.
+pub(super) fn negotiate(
+    request: &HttpRequest,
+    factories: &[ExtensionFactory],
+) -> Result<Negotiated, ServerError> {
+    let offers = parse_offers(request.headers.get_all("Sec-WebSocket-Extensions")).ok_or(
+        ServerError::HandshakeValidation("the Sec-WebSocket-Extensions header is malformed"),
+    )?;
+
+    let mut accepted: Vec<Box<dyn Extension>> = Vec::new();
+    let mut responses = Vec::new();
+    let mut rsv = RsvBits::NONE;
+    for (name, params) in offers {
+        if accepted
+            .iter()
+            .any(|extension| extension.name().eq_ignore_ascii_case(&name))
+        {
+            continue;
+        }
+
+        for factory in factories {
+            let mut extension = factory.make();
+            if !extension.name().eq_ignore_ascii_case(&name) || extension.rsv_bits().intersects(rsv)
+            {
+                continue;
+            }
+            if let Some(response) = extension.negotiate(&params) {
+                let response: Vec<String> = std::iter::once(extension.name().to_string())
+                    .chain(response.iter().map(ExtensionParam::to_string))
+                    .collect();
+                responses.push(response.join("; "));
+                rsv = rsv.union(extension.rsv_bits());
+                accepted.push(extension);
+                break;
+            }
+        }
+    }
+
+    let response = (!responses.is_empty()).then(|| responses.join(", "));
+    Ok((accepted, response))
+}
+
+/// The extensions offered in `Sec-WebSocket-Extensions` values, each with its parameters. `None`
+/// if any of them is malformed.
+fn parse_offers<'a>(
+    values: impl Iterator<Item = &'a str>,
+) -> Option<Vec<(String, Vec<ExtensionParam>)>> {
+    values
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        // Empty list elements are allowed and mean nothing
+        .filter(|offer| !offer.is_empty())
+        .map(|offer| {
+            let mut parts = offer.split(';').map(str::trim);
+            let name = parts.next().filter(|name| is_token(name))?;
+            let params = parts.map(parse_param).collect::<Option<_>>()?;
+            Some((name.to_string(), params))
+        })
+        .collect()
+}
+
+/// Parses `name` or `name=value`, where the value is a token that may be quoted
+fn parse_param(param: &str) -> Option<ExtensionParam> {
+    let (name, value) = match param.split_once('=') {
+        Some((name, value)) => (name.trim_end(), Some(value.trim_start())),
+        None => (param, None),
+    };
+    if !is_token(name) {
+        return None;
+    }
+
+    let value = match value {
+        Some(value) => {
+            let unquoted = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value);
+            // RFC 6455 only allows values that are tokens once unquoted
+            is_token(unquoted).then_some(Some(unquoted))?
+        }
+        None => None,
+    };
+    Some(ExtensionParam::new(name, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Fake {
+        name: &'static str,
+        rsv_bits: RsvBits,
+    }
+
+    impl Extension for Fake {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn rsv_bits(&self) -> RsvBits {
+            self.rsv_bits
+        }
+
+        /// Declines offers with a `decline` parameter, and accepts the others with their
+        /// parameters echoed back
+        fn negotiate(&mut self, params: &[ExtensionParam]) -> Option<Vec<ExtensionParam>> {
+            match params.iter().any(|param| param.name == "decline") {
+                true => None,
+                false => Some(params.to_vec()),
+            }
+        }
+    }
+
+    fn factory(name: &'static str, rsv1: bool) -> ExtensionFactory {
+        let rsv_bits = RsvBits {
+            rsv1,
+            ..RsvBits::NONE
+        };
+        ExtensionFactory::new(move || Box::new(Fake { name, rsv_bits }))
+    }
+
+    fn negotiated(offers: &str, factories: &[ExtensionFactory]) -> Option<Option<String>> {
+        let request =
+            format!("GET / HTTP/1.1\r\nHost: a\r\nSec-WebSocket-Extensions: {offers}\r\n\r\n");
+        let request = HttpRequest::build(&mut request.as_bytes()).unwrap();
+        let (extensions, response) = negotiate(&request, factories).ok()?;
+        assert_eq!(
+            extensions.len(),
+            response.iter().flat_map(|r| r.split(',')).count()
+        );
+        Some(response)
+    }
+
+    #[test]
+    fn offers_are_parsed_with_their_parameters() {
+        let offers = parse_offers(
+            [
+                "x-deflate; client_max_window_bits; server_max_window_bits=\"10\"",
+                ", x-other",
+            ]
+            .into_iter(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            offers,
+            [
+                (
+                    "x-deflate".to_string(),
+                    vec![
+                        ExtensionParam::new("client_max_window_bits", None),
+                        ExtensionParam::new("server_max_window_bits", Some("10")),
+                    ]
+                ),
+                ("x-other".to_string(), vec![]),
+            ]
+        );
+        for malformed in [
+            "x-a;",
+            "x a",
+            "x-a; b=",
+            "x-a; b=\"c d\"",
+            "x-a; =1",
+            "x-a; b=\"1",
+        ] {
+            assert_eq!(parse_offers([malformed].into_iter()), None, "{malformed:?}");
+        }
+    }
+
+    #[test]
+    fn the_first_acceptable_offer_is_taken() {
+        let factories = [factory("x-a", true), factory("x-b", false)];
+
+        assert_eq!(
+            negotiated("x-a; decline, x-c, x-a; level=1, x-a, x-b", &factories),
+            Some(Some("x-a; level=1, x-b".to_string()))
+        );
+        assert_eq!(negotiated("x-c", &factories), Some(None));
+        assert_eq!(negotiated("x-a;;", &factories), None);
+    }
+
+    #[test]
+    fn reserved_bits_are_only_claimed_once() {
+        let factories = [factory("x-a", true), factory("x-b", true)];
+
+        assert_eq!(
+            negotiated("x-b, x-a", &factories),
+            Some(Some("x-b".to_string()))
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use super::{Connection, ServerError};
+use crate::http::HttpRequest;
+use crate::websocket::{CloseFrame, Message};
+
+/// The application's side of a server. One handler is shared by every connection, so anything it
+/// keeps per connection has to be keyed by something like [`Connection::peer_addr`], which is also
+/// what [`on_close`](Handler::on_close) and [`on_error`](Handler::on_error) are given to clean up
+/// after it.
+///
+/// Every method does nothing by default, so a handler only implements the ones it cares about.
+/// A panic in any of them only ends the connection it was called for.
+pub trait Handler: Send + Sync + 'static {
+    /// Picks the handler that serves `request`, filling in any parameters its path matched.
+    /// Handshakes that no handler is found for are rejected with a 404.
+    ///
+    /// Every request is served by this handler unless it's overridden, as
+    /// [`Router`](super::Router) and [`VirtualHosts`](super::VirtualHosts) do.
+    fn handler_for<'a>(
+        &'a self,
+        _request: &HttpRequest,
+        _params: &mut HashMap<String, String>,
+    ) -> Option<&'a dyn Handler>
+    where
+        Self: Sized,
+    {
+        Some(self)
+    }
+
+    /// Called once the handshake has been accepted, before any messages are read
+    fn on_open(&self, _connection: &mut Connection, _request: &HttpRequest) {}
+
+    /// Called for every message from the client other than its close. Pings have already been
+    /// answered by the time they get here.
+    fn on_message(&self, _connection: &mut Connection, _message: Message) {}
+
+    /// Called once a connection that was opened has ended. If the client closed it without giving
+    /// a status code the code is [`CloseCode::NoStatusReceived`](crate::websocket::CloseCode).
+    /// If the server failed the connection it's the code the server closed it with, such as
+    /// [`CloseCode::ProtocolError`](crate::websocket::CloseCode), and if it ended without a close
+    /// at all it's [`CloseCode::Abnormal`](crate::websocket::CloseCode).
+    fn on_close(&self, _peer_addr: SocketAddr, _frame: CloseFrame) {}
+
+    /// Called when a handshake from `peer_addr` is rejected or its connection fails
+    fn on_error(&self, peer_addr: SocketAddr, error: &ServerError) {
+        eprintln!("{peer_addr}: {error}");
+    }
+}
+
+/// A boxed handler that can still pick the handler for a request. [`Handler::handler_for`] can't be
+/// called through `dyn Handler`, so handlers that hand requests on to other routing handlers box
+/// them as this instead.
+pub(crate) trait Routes: Send + Sync {
+    fn route<'a>(
+        &'a self,
+        request: &HttpRequest,
+        params: &mut HashMap<String, String>,
+    ) -> Option<&'a dyn Handler>;
+}
+
+impl<H: Handler> Routes for H {
+    fn route<'a>(
+        &'a self,
+        request: &HttpRequest,
+        params: &mut HashMap<String, String>,
+    ) -> Option<&'a dyn Handler> {
+        Handler::handler_for(self, request, params)
+    }
+}
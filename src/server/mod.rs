@@ -0,0 +1,936 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Display;
+use std::io::{prelude::*, BufRead, BufReader};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::http::{
+    build_http_response, HeaderMap, HttpMethod, HttpParseError, HttpRequest, HttpVersion,
+};
+use crate::websocket::{
+    CloseCode, CloseFrame, Extension, ExtensionFactory, Heartbeat, Message, Shutdown, WebSocket,
+    WebSocketConfig, WebSocketError,
+};
+use crate::{base64, sha1};
+
+mod accept;
+mod connection;
+#[cfg(target_os = "linux")]
+mod epoll;
+#[cfg(target_os = "linux")]
+mod event_loop;
+mod extensions;
+mod handler;
+mod origin;
+mod router;
+mod virtual_hosts;
+
+pub use connection::Connection;
+#[cfg(target_os = "linux")]
+pub use event_loop::EventLoop;
+pub use handler::Handler;
+pub use origin::{OriginPolicy, OriginPredicate};
+pub use router::Router;
+pub use virtual_hosts::VirtualHosts;
+
+use accept::AcceptBackoff;
+use connection::Stream;
+
+/// Settings shared by every connection a [`Server`] accepts
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ServerConfig {
+    /// How many connections are served at once. Connections accepted beyond this are answered
+    /// with a 503 and closed.
+    pub max_connections: usize,
+    /// How long a client has to send its whole opening handshake. Connections that haven't by
+    /// then are closed, so that idle clients can't hold on to connection slots.
+    pub handshake_timeout: Duration,
+    /// The origins handshakes are accepted from. Handshakes from any other origin are answered
+    /// with a 403.
+    pub origins: OriginPolicy,
+    /// The host names the server answers to, compared without regard to case. Handshakes for any
+    /// other host are answered with a 421, which keeps DNS rebinding attacks out. Every host is
+    /// answered when this is empty.
+    pub server_names: Vec<String>,
+    /// The subprotocols the server speaks, most preferred first. Each handshake gets the first of
+    /// these that the client offered in its `Sec-WebSocket-Protocol`, or no subprotocol if it
+    /// offered none of them.
+    pub subprotocols: Vec<String>,
+    /// The extensions the server supports. Every connection gets its own instance of each
+    /// extension it negotiates.
+    pub extensions: Vec<ExtensionFactory>,
+    pub websocket: WebSocketConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            max_connections: 1024,
+            handshake_timeout: Duration::from_secs(10),
+            origins: OriginPolicy::Any,
+            server_names: Vec::new(),
+            subprotocols: Vec::new(),
+            extensions: Vec::new(),
+            websocket: WebSocketConfig {
+                heartbeat: Some(Heartbeat {
+                    interval: Duration::from_secs(30),
+                    timeout: Duration::from_secs(10),
+                }),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// Accepts websocket connections and serves each of them on its own thread, up to
+/// `max_connections` at a time, handing them to `H`
+#[derive(Debug)]
+pub struct Server<H> {
+    handler: Arc<H>,
+    config: Arc<ServerConfig>,
+    connections: Arc<AtomicUsize>,
+}
+
+impl<H: Handler> Server<H> {
+    pub fn new(handler: H) -> Server<H> {
+        Server::with_config(handler, ServerConfig::default())
+    }
+
+    pub fn with_config(handler: H, config: ServerConfig) -> Server<H> {
+        Server {
+            handler: Arc::new(handler),
+            config: Arc::new(config),
+            connections: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Binds to `addr` and serves connections on it, see [`serve`](Server::serve)
+    pub fn listen<A: ToSocketAddrs>(&self, addr: A) -> Result<(), ServerError> {
+        self.serve(TcpListener::bind(addr)?)
+    }
+
+    /// Serves connections from an already bound listener. Anything that goes wrong with a
+    /// connection, including a panic or its thread failing to start, is reported and only ends
+    /// that connection, and failing to accept one is retried. This only returns if the listener
+    /// itself can't be used.
+    pub fn serve(&self, listener: TcpListener) -> Result<(), ServerError> {
+        let mut backoff = AcceptBackoff::new();
+
+        loop {
+            let (mut stream, addr) = match listener.accept() {
+                Ok(accepted) => {
+                    backoff.succeeded();
+                    accepted
+                }
+                Err(error) => {
+                    thread::sleep(backoff.failed(error)?);
+                    continue;
+                }
+            };
+
+            let guard =
+                match ConnectionGuard::acquire(&self.connections, self.config.max_connections) {
+                    Some(guard) => guard,
+                    None => {
+                        let _ = stream.write_all(at_capacity_response().as_bytes());
+                        continue;
+                    }
+                };
+
+            // Kept to refuse the connection with if its thread can't be started, by which point
+            // the stream itself has been dropped along with the thread's closure
+            let spare = stream.try_clone();
+            let handler = Arc::clone(&self.handler);
+            let config = Arc::clone(&self.config);
+            let spawned = thread::Builder::new().spawn(move || {
+                let _guard = guard;
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    handle_client(stream, addr, &*handler, &config)
+                }));
+                if result.is_err() {
+                    eprintln!("{addr}: the connection panicked and has been closed");
+                }
+            });
+            if let Err(error) = spawned {
+                eprintln!("{addr}: couldn't start a thread for the connection, {error}");
+                if let Ok(mut stream) = spare {
+                    let _ = stream.write_all(at_capacity_response().as_bytes());
+                }
+            }
+        }
+    }
+}
+
+/// Holds one of the server's connection slots, giving it back when dropped so that a connection
+/// ending in an error or a panic still frees its slot
+struct ConnectionGuard {
+    connections: Arc<AtomicUsize>,
+}
+
+impl ConnectionGuard {
+    fn acquire(connections: &Arc<AtomicUsize>, max_connections: usize) -> Option<ConnectionGuard> {
+        connections
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                (count < max_connections).then_some(count + 1)
+            })
+            .ok()?;
+        Some(ConnectionGuard {
+            connections: Arc::clone(connections),
+        })
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// A stream that can only be read from until a deadline. Each read times out when the deadline
+/// does, so a client sending nothing, or a byte at a time, can't make the handshake take longer.
+struct DeadlineStream {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl Read for DeadlineStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(std::io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+/// How long a write can block for when the heartbeat doesn't give a timeout to use instead
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A connection's socket once its handshake is done. Writes time out if the client stops reading,
+/// and once one has the client is given up on: every read and write fails from then on, rather
+/// than timing out in a way that would be retried.
+#[derive(Debug)]
+struct BlockingStream {
+    stream: TcpStream,
+    stalled: bool,
+}
+
+impl BlockingStream {
+    fn stalled_error() -> std::io::Error {
+        std::io::Error::other("the client isn't reading what's sent to it")
+    }
+}
+
+impl Read for BlockingStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.stalled {
+            return Err(BlockingStream::stalled_error());
+        }
+        self.stream.read(buf)
+    }
+}
+
+impl Write for BlockingStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.stalled {
+            return Err(BlockingStream::stalled_error());
+        }
+        match self.stream.write(buf) {
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                self.stalled = true;
+                Err(BlockingStream::stalled_error())
+            }
+            result => result,
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Shutdown for BlockingStream {
+    fn shutdown_write(&mut self) -> std::io::Result<()> {
+        self.stream.shutdown_write()
+    }
+}
+
+/// An opening handshake that has been accepted
+struct Handshake<'a> {
+    request: HttpRequest,
+    /// The response that accepts it
+    response: String,
+    /// The handler the request's path was routed to
+    handler: &'a dyn Handler,
+    params: HashMap<String, String>,
+    /// The subprotocol chosen for the connection
+    protocol: Option<String>,
+    /// The extensions negotiated for the connection
+    extensions: Vec<Box<dyn Extension>>,
+}
+
+/// Serves a connection from its handshake to its end. Errors are reported to the handler the
+/// connection was routed to, or to `handler` itself if it never got that far.
+fn handle_client<H: Handler>(
+    stream: TcpStream,
+    addr: SocketAddr,
+    handler: &H,
+    config: &ServerConfig,
+) {
+    // Without a write timeout, a client that stops reading would block its thread for good
+    let write_timeout = match config.websocket.heartbeat {
+        Some(heartbeat) => heartbeat.timeout,
+        None => WRITE_TIMEOUT,
+    };
+    if let Err(error) = stream.set_write_timeout(Some(write_timeout)) {
+        return handler.on_error(addr, &error.into());
+    }
+
+    // The reader used for the handshake is handed on to the websocket, so nothing the client sent
+    // straight after the handshake is lost
+    let mut reader = BufReader::new(DeadlineStream {
+        stream,
+        deadline: Instant::now() + config.handshake_timeout,
+    });
+    let handshake = match read_handshake(&mut reader, handler, config) {
+        Ok(handshake) => handshake,
+        Err(error) => {
+            if let Some(response) = error.response() {
+                let _ = reader.get_mut().stream.write_all(response.as_bytes());
+            }
+            return handler.on_error(addr, &error);
+        }
+    };
+
+    let route = handshake.handler;
+    if let Err(error) = run_connection(reader, addr, handshake, config) {
+        route.on_error(addr, &error);
+    }
+}
+
+/// Accepts the handshake and hands everything the client sends to the handler it was routed to,
+/// until the connection ends
+fn run_connection(
+    reader: BufReader<DeadlineStream>,
+    addr: SocketAddr,
+    handshake: Handshake,
+    config: &ServerConfig,
+) -> Result<(), ServerError> {
+    let buffered = reader.buffer().to_vec();
+    let mut stream = reader.into_inner().stream;
+    stream.write_all(handshake.response.as_bytes())?;
+
+    // The heartbeat runs when reads time out on an idle connection
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let stream: Box<dyn Stream> = Box::new(BlockingStream {
+        stream,
+        stalled: false,
+    });
+    let mut websocket = WebSocket::from_partially_read(stream, &buffered, config.websocket);
+    websocket.set_extensions(handshake.extensions);
+    let mut connection = Connection::new(
+        websocket,
+        addr,
+        &handshake.request,
+        handshake.params,
+        handshake.protocol,
+    );
+    let handler = handshake.handler;
+    handler.on_open(&mut connection, &handshake.request);
+
+    loop {
+        match connection.read_message() {
+            Ok(message) => {
+                if !dispatch(handler, &mut connection, message) {
+                    return Ok(());
+                }
+            }
+            Err(error) if error.is_timeout() => continue,
+            Err(error) => {
+                let error = error.into();
+                handler.on_close(addr, failed_close(&error));
+                return Err(error);
+            }
+        }
+    }
+}
+
+/// Reads and validates the opening handshake, routing it and building the response that accepts
+/// it. If the handshake is rejected, the error's [`response`](ServerError::response) tells the
+/// client why.
+fn read_handshake<'a, R: BufRead, H: Handler>(
+    reader: &mut R,
+    handler: &'a H,
+    config: &ServerConfig,
+) -> Result<Handshake<'a>, ServerError> {
+    let request = HttpRequest::build(reader)?;
+
+    validate_handshake(&request)?;
+    if !config.server_names.is_empty() {
+        let host = request.host().unwrap_or_default();
+        if !config
+            .server_names
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case(host))
+        {
+            return Err(ServerError::UnknownHost);
+        }
+    }
+    if !config.origins.allows(request.headers.get("Origin")) {
+        return Err(ServerError::OriginNotAllowed);
+    }
+
+    // Routed last, so that a request that isn't a websocket handshake at all says so rather than
+    // that nothing is at the path
+    let mut params = HashMap::new();
+    let handler = handler
+        .handler_for(&request, &mut params)
+        .ok_or(ServerError::NotFound)?;
+
+    // we can safely unwrap here because we've validated the key in validate_handshake.
+    // TODO consider a more appropriate way to handle this checking to take advantage of the type
+    // system
+    let websocket_key = calculate_websocket_key(request.headers.get("Sec-WebSocket-Key").unwrap());
+    let mut headers = HeaderMap::new();
+    headers.append("Upgrade", "websocket");
+    headers.append("Connection", "Upgrade");
+    headers.append("Sec-WebSocket-Accept", &websocket_key);
+    let protocol = choose_subprotocol(&request, &config.subprotocols);
+    if let Some(protocol) = &protocol {
+        headers.append("Sec-WebSocket-Protocol", protocol);
+    }
+    let (extensions, accepted) = extensions::negotiate(&request, &config.extensions)?;
+    if let Some(accepted) = &accepted {
+        headers.append("Sec-WebSocket-Extensions", accepted);
+    }
+    let response = build_http_response(101, "Switching Protocols", headers, "");
+
+    Ok(Handshake {
+        request,
+        response,
+        handler,
+        params,
+        protocol,
+        extensions,
+    })
+}
+
+/// The first of the server's `subprotocols` that the client offered
+fn choose_subprotocol(request: &HttpRequest, subprotocols: &[String]) -> Option<String> {
+    subprotocols
+        .iter()
+        .find(|protocol| {
+            request
+                .headers
+                .tokens("Sec-WebSocket-Protocol")
+                .any(|offered| offered == protocol.as_str())
+        })
+        .cloned()
+}
+
+/// The response for a connection accepted while the server is already serving as many as it can
+fn at_capacity_response() -> String {
+    build_http_response(
+        503,
+        "Service Unavailable",
+        HeaderMap::new(),
+        "The server is at capacity, try again later\n",
+    )
+}
+
+/// Hands a message from the client to the handler. Returns false once the client has closed the
+/// connection.
+fn dispatch(handler: &dyn Handler, connection: &mut Connection, message: Message) -> bool {
+    match message {
+        Message::Close(frame) => {
+            let frame = frame.unwrap_or(CloseFrame {
+                code: CloseCode::NoStatusReceived,
+                reason: String::new(),
+            });
+            handler.on_close(connection.peer_addr(), frame);
+            false
+        }
+        message => {
+            handler.on_message(connection, message);
+            true
+        }
+    }
+}
+
+/// What's reported to [`Handler::on_close`] when a connection ends in `error`. That's the code
+/// the connection was failed with if the error sent a close frame for it, or
+/// [`CloseCode::Abnormal`] if the connection ended without one.
+fn failed_close(error: &ServerError) -> CloseFrame {
+    let code = match error {
+        ServerError::WebSocket(error) => error.close_code(),
+        _ => None,
+    };
+    CloseFrame {
+        code: code.unwrap_or(CloseCode::Abnormal),
+        reason: String::new(),
+    }
+}
+
+fn validate_handshake(request: &HttpRequest) -> Result<(), ServerError> {
+    if let HttpMethod::GET = request.method {
+    } else {
+        return Err(ServerError::MethodNotAllowed);
+    }
+
+    if request.http_version < HttpVersion::HTTP_1_1 {
+        return Err(ServerError::UnsupportedHttpVersion);
+    }
+
+    match request.headers.get_all("Host").count() {
+        0 => {
+            return Err(ServerError::HandshakeValidation(
+                "the Host header is missing",
+            ))
+        }
+        1 if request.host().is_some() => {}
+        _ => {
+            return Err(ServerError::HandshakeValidation(
+                "the Host header must name one valid host",
+            ))
+        }
+    }
+    // The Host header is what the server names and virtual hosts are checked against, so a target
+    // in absolute form can't be allowed to name some other host
+    if let Some(authority) = request.uri.authority() {
+        if !request
+            .headers
+            .get("Host")
+            .is_some_and(|host| host.eq_ignore_ascii_case(authority))
+        {
+            return Err(ServerError::HandshakeValidation(
+                "the request target and the Host header name different hosts",
+            ));
+        }
+    }
+
+    if !request.headers.has_token("Connection", "Upgrade") {
+        return Err(ServerError::HandshakeValidation(
+            "the Connection header must include Upgrade",
+        ));
+    }
+
+    if !request.headers.has_token("Upgrade", "websocket") {
+        return Err(ServerError::HandshakeValidation(
+            "the Upgrade header must include websocket",
+        ));
+    }
+
+    match request.headers.get("Sec-WebSocket-Key") {
+        // The key is a random 16 byte nonce
+        Some(key) if base64::decode(key).is_ok_and(|key| key.len() == 16) => {}
+        Some(_) => {
+            return Err(ServerError::HandshakeValidation(
+                "the Sec-WebSocket-Key header must be 16 bytes in base64",
+            ))
+        }
+        None => {
+            return Err(ServerError::HandshakeValidation(
+                "the Sec-WebSocket-Key header is missing",
+            ))
+        }
+    }
+
+    match request.headers.get("Sec-WebSocket-Version") {
+        Some("13") => {}
+        _ => return Err(ServerError::UnsupportedWebSocketVersion),
+    }
+
+    Ok(())
+}
+
+static MAGIC_KEY_STRING: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+pub fn calculate_websocket_key(client_key: &str) -> String {
+    // concat client key with magic key
+    let to_hash = format!("{client_key}{MAGIC_KEY_STRING}");
+    let hash = sha1::hash(&to_hash);
+    base64::encode(hash)
+}
+
+#[derive(Debug)]
+pub enum ServerError {
+    /// The request isn't well formed HTTP/1.1
+    HttpRequestParse(HttpParseError),
+    /// The request target isn't a URI, or isn't in a form a websocket can be opened with
+    InvalidUri,
+    /// The request isn't a valid websocket handshake, for the given reason
+    HandshakeValidation(&'static str),
+    InvalidHttpMethod,
+    MethodNotAllowed,
+    /// The handshake came from an origin the server's [`OriginPolicy`] doesn't allow
+    OriginNotAllowed,
+    /// The handshake was for a host that isn't one of the server's names
+    UnknownHost,
+    /// The request was made with a version of HTTP before 1.1, which can't be upgraded
+    UnsupportedHttpVersion,
+    UnsupportedWebSocketVersion,
+    NotFound,
+    /// The connection failed after the handshake
+    WebSocket(WebSocketError),
+    IO(std::io::Error),
+}
+
+impl ServerError {
+    /// The response telling the client why its handshake was rejected, or `None` if the error
+    /// happened somewhere a response can't be sent
+    pub fn response(&self) -> Option<String> {
+        let mut headers = HeaderMap::new();
+        let (code, description) = match self {
+            ServerError::HttpRequestParse(HttpParseError::RequestLineTooLong) => {
+                (414, "URI Too Long")
+            }
+            ServerError::HttpRequestParse(error) if error.is_too_large() => {
+                (431, "Request Header Fields Too Large")
+            }
+            ServerError::HttpRequestParse(_)
+            | ServerError::InvalidUri
+            | ServerError::HandshakeValidation(_)
+            | ServerError::InvalidHttpMethod => (400, "Bad Request"),
+            ServerError::MethodNotAllowed => {
+                headers.append("Allow", "GET");
+                (405, "Method Not Allowed")
+            }
+            ServerError::OriginNotAllowed => (403, "Forbidden"),
+            ServerError::UnknownHost => (421, "Misdirected Request"),
+            ServerError::UnsupportedHttpVersion => (505, "HTTP Version Not Supported"),
+            ServerError::UnsupportedWebSocketVersion => {
+                headers.append("Upgrade", "websocket");
+                headers.append("Sec-WebSocket-Version", "13");
+                (426, "Upgrade Required")
+            }
+            ServerError::NotFound => (404, "Not Found"),
+            ServerError::WebSocket(_) | ServerError::IO(_) => return None,
+        };
+        Some(build_http_response(
+            code,
+            description,
+            headers,
+            &format!("{self}\n"),
+        ))
+    }
+}
+
+impl From<WebSocketError> for ServerError {
+    fn from(error: WebSocketError) -> ServerError {
+        ServerError::WebSocket(error)
+    }
+}
+
+impl From<std::io::Error> for ServerError {
+    fn from(error: std::io::Error) -> ServerError {
+        ServerError::IO(error)
+    }
+}
+
+impl Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServerError::HttpRequestParse(error) => {
+                write!(f, "Error while parsing the HTTP request, {error}")
+            }
+            ServerError::InvalidUri => {
+                write!(f, "The request target is not a valid URI")
+            }
+            ServerError::HandshakeValidation(reason) => {
+                write!(f, "Invalid websocket handshake, {reason}")
+            }
+            ServerError::InvalidHttpMethod => {
+                write!(f, "Invalid HTTP method in request")
+            }
+            ServerError::MethodNotAllowed => {
+                write!(f, "Websocket handshakes must use the GET method")
+            }
+            ServerError::OriginNotAllowed => {
+                write!(f, "Websockets can't be opened from this origin")
+            }
+            ServerError::UnknownHost => {
+                write!(f, "This server doesn't serve the requested host")
+            }
+            ServerError::UnsupportedHttpVersion => {
+                write!(f, "Websocket handshakes need HTTP/1.1 or later")
+            }
+            ServerError::UnsupportedWebSocketVersion => {
+                write!(f, "Only version 13 of the websocket protocol is supported")
+            }
+            ServerError::NotFound => {
+                write!(f, "No websocket is served at this path")
+            }
+            ServerError::WebSocket(err) => err.fmt(f),
+            ServerError::IO(err) => err.fmt(f),
+        }
+    }
+}
+
+impl Error for ServerError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::MAX_HEADER_BYTES;
+    use std::net::TcpListener;
+
+    #[test]
+    fn frame_sent_with_handshake_is_kept() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        // A masked "Hello" text frame sent in the same write as the handshake
+        let mut request = b"GET /chat HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec();
+        request.extend([
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ]);
+        client.write_all(&request).unwrap();
+
+        let mut reader = BufReader::new(server);
+        let request = HttpRequest::build(&mut reader).unwrap();
+        assert_eq!(request.uri.path(), "/chat");
+
+        let mut ws = WebSocket::from_reader(reader, WebSocketConfig::default());
+        assert_eq!(ws.read_message().unwrap(), Message::Text("Hello".into()));
+    }
+
+    #[test]
+    fn calculate_websocket_key_works() {
+        let calculated = calculate_websocket_key("dGhlIHNhbXBsZSBub25jZQ==");
+        let expected = "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=";
+
+        assert_eq!(calculated, expected);
+    }
+
+    const HANDSHAKE: &str = "GET /chat HTTP/1.1\r\n\
+                             Host: localhost\r\n\
+                             Upgrade: websocket\r\n\
+                             Connection: Upgrade\r\n\
+                             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                             Sec-WebSocket-Version: 13\r\n\r\n";
+
+    struct Nothing;
+
+    impl Handler for Nothing {}
+
+    /// The status line of the response to `request`, whether it's accepted or rejected
+    fn status_line<H: Handler>(request: &str, handler: &H) -> String {
+        status_line_with_config(request, handler, &ServerConfig::default())
+    }
+
+    fn status_line_with_config<H: Handler>(
+        request: &str,
+        handler: &H,
+        config: &ServerConfig,
+    ) -> String {
+        let response = match read_handshake(&mut request.as_bytes(), handler, config) {
+            Ok(handshake) => handshake.response,
+            Err(error) => error.response().unwrap(),
+        };
+        response.lines().next().unwrap().to_string()
+    }
+
+    #[test]
+    fn rejected_handshakes_get_matching_responses() {
+        let handler = &Nothing;
+
+        assert_eq!(
+            status_line(HANDSHAKE, handler),
+            "HTTP/1.1 101 Switching Protocols"
+        );
+        assert_eq!(
+            status_line(&HANDSHAKE.replacen("GET", "POST", 1), handler),
+            "HTTP/1.1 405 Method Not Allowed"
+        );
+        assert_eq!(
+            status_line(&HANDSHAKE.replacen("HTTP/1.1", "HTTP/1.0", 1), handler),
+            "HTTP/1.1 505 HTTP Version Not Supported"
+        );
+        assert_eq!(
+            status_line(&HANDSHAKE.replacen("HTTP/1.1", "HTTP/1.2", 1), handler),
+            "HTTP/1.1 101 Switching Protocols"
+        );
+        assert_eq!(
+            status_line(&HANDSHAKE.replace("Version: 13", "Version: 8"), handler),
+            "HTTP/1.1 426 Upgrade Required"
+        );
+        assert_eq!(
+            status_line(
+                &HANDSHAKE.replace("Upgrade: websocket", "Upgrade: h2c"),
+                handler
+            ),
+            "HTTP/1.1 400 Bad Request"
+        );
+        for key in [
+            "",
+            "dGhlIHNhbXBsZSBub25jZQ",
+            "dGhlIHNhbXBsZSBub25jZSE=",
+            "dGhlIHNhbXBsZSBub25jZR==",
+        ] {
+            let handshake = HANDSHAKE.replace("dGhlIHNhbXBsZSBub25jZQ==", key);
+            assert_eq!(
+                status_line(&handshake, handler),
+                "HTTP/1.1 400 Bad Request",
+                "{key:?}"
+            );
+        }
+        assert_eq!(
+            status_line("GET /chat\r\n\r\n", handler),
+            "HTTP/1.1 400 Bad Request"
+        );
+        let cookie = format!("Cookie: {}\r\n\r\n", "a".repeat(MAX_HEADER_BYTES));
+        assert_eq!(
+            status_line(&HANDSHAKE.replace("\r\n\r\n", &cookie), handler),
+            "HTTP/1.1 431 Request Header Fields Too Large"
+        );
+    }
+
+    #[test]
+    fn header_names_and_tokens_ignore_case() {
+        let handshake = HANDSHAKE
+            .replace("Upgrade: websocket", "upgrade: WebSocket")
+            .replace("Connection: Upgrade", "connection: keep-alive, upgrade");
+
+        assert_eq!(
+            status_line(&handshake, &Nothing),
+            "HTTP/1.1 101 Switching Protocols"
+        );
+        assert_eq!(
+            status_line(
+                &HANDSHAKE.replace("Connection: Upgrade", "Connection: Upgraded"),
+                &Nothing
+            ),
+            "HTTP/1.1 400 Bad Request"
+        );
+    }
+
+    #[test]
+    fn hosts_are_checked_against_the_server_names() {
+        let config = ServerConfig {
+            server_names: vec!["localhost".to_string(), "example.com".to_string()],
+            ..Default::default()
+        };
+        let to = |host: &str| {
+            let handshake = HANDSHAKE.replace("Host: localhost", host);
+            status_line_with_config(&handshake, &Nothing, &config)
+        };
+
+        assert_eq!(
+            to("Host: EXAMPLE.com:8080"),
+            "HTTP/1.1 101 Switching Protocols"
+        );
+        assert_eq!(
+            to("Host: attacker.example"),
+            "HTTP/1.1 421 Misdirected Request"
+        );
+        assert_eq!(to("X-Host: localhost"), "HTTP/1.1 400 Bad Request");
+        assert_eq!(to("Host: a b"), "HTTP/1.1 400 Bad Request");
+        assert_eq!(
+            to("Host: localhost\r\nHost: example.com"),
+            "HTTP/1.1 400 Bad Request"
+        );
+
+        let absolute = |host: &str| {
+            let handshake = HANDSHAKE
+                .replace("GET /chat", "GET ws://EXAMPLE.com/chat")
+                .replace("Host: localhost", host);
+            status_line_with_config(&handshake, &Nothing, &config)
+        };
+        assert_eq!(
+            absolute("Host: example.com"),
+            "HTTP/1.1 101 Switching Protocols"
+        );
+        assert_eq!(
+            absolute("Host: attacker.example"),
+            "HTTP/1.1 400 Bad Request"
+        );
+        assert_eq!(absolute("Host: localhost"), "HTTP/1.1 400 Bad Request");
+    }
+
+    #[test]
+    fn the_preferred_subprotocol_is_chosen() {
+        let config = ServerConfig {
+            subprotocols: vec!["graphql-ws".to_string(), "json.v1".to_string()],
+            ..Default::default()
+        };
+        let chosen = |offer: &str| {
+            let handshake = HANDSHAKE.replace("\r\n\r\n", &format!("\r\n{offer}\r\n\r\n"));
+            let handshake = read_handshake(&mut handshake.as_bytes(), &Nothing, &config).unwrap();
+            let echoed = handshake
+                .response
+                .lines()
+                .find_map(|line| line.strip_prefix("Sec-WebSocket-Protocol: "))
+                .map(str::to_string);
+            assert_eq!(echoed, handshake.protocol);
+            handshake.protocol
+        };
+
+        assert_eq!(
+            chosen("Sec-WebSocket-Protocol: json.v1, graphql-ws").as_deref(),
+            Some("graphql-ws")
+        );
+        assert_eq!(
+            chosen("Sec-WebSocket-Protocol: mqtt\r\nSec-WebSocket-Protocol: json.v1").as_deref(),
+            Some("json.v1")
+        );
+        assert_eq!(chosen("Sec-WebSocket-Protocol: mqtt, JSON.V1"), None);
+        assert_eq!(chosen("X-Nothing: offered"), None);
+    }
+
+    #[test]
+    fn disallowed_origins_are_forbidden() {
+        let config = ServerConfig {
+            origins: OriginPolicy::allow(["https://example.com"]),
+            ..Default::default()
+        };
+        let from = |origin: &str| {
+            let handshake = HANDSHAKE.replace("\r\n\r\n", &format!("\r\nOrigin: {origin}\r\n\r\n"));
+            status_line_with_config(&handshake, &Nothing, &config)
+        };
+
+        assert_eq!(
+            from("https://example.com"),
+            "HTTP/1.1 101 Switching Protocols"
+        );
+        assert_eq!(from("https://evil.example"), "HTTP/1.1 403 Forbidden");
+        assert_eq!(
+            status_line_with_config(HANDSHAKE, &Nothing, &config),
+            "HTTP/1.1 403 Forbidden"
+        );
+    }
+
+    #[test]
+    fn unknown_paths_are_not_found() {
+        let handler = &Router::new().route("/chat", Nothing);
+
+        assert_eq!(
+            status_line(&HANDSHAKE.replace("/chat", "/chat?room=1"), handler),
+            "HTTP/1.1 101 Switching Protocols"
+        );
+        assert_eq!(
+            status_line(&HANDSHAKE.replace("/chat", "/news"), handler),
+            "HTTP/1.1 404 Not Found"
+        );
+    }
+
+    #[test]
+    fn rejection_explains_itself() {
+        let response = ServerError::UnsupportedWebSocketVersion.response().unwrap();
+        let body = "Only version 13 of the websocket protocol is supported\n";
+
+        assert!(response.contains("\r\nSec-WebSocket-Version: 13\r\n"));
+        assert!(response.contains(&format!("\r\nContent-Length: {}\r\n", body.len())));
+        assert!(response.ends_with(&format!("\r\n\r\n{body}")));
+    }
+}
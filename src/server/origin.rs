@@ -0,0 +1,143 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+
+/// Decides whether a handshake with the given `Origin` is allowed
+pub type OriginPredicate = dyn Fn(Option<&str>) -> bool + Send + Sync;
+
+/// Which origins websockets can be opened from, checked against the `Origin` header browsers send
+/// with every handshake. Without a policy, any web page the user visits can open a websocket to the
+/// server with the user's cookies.
+///
+/// Clients other than browsers usually don't send an `Origin` at all. Only [`OriginPolicy::Any`]
+/// lets them connect, unless a custom predicate allows it.
+#[derive(Clone, Default)]
+pub enum OriginPolicy {
+    /// Any origin, or none, is allowed
+    #[default]
+    Any,
+    /// Only origins matching one of these patterns are allowed, see [`OriginPolicy::allow`]
+    Allow(Vec<String>),
+    /// Origins are allowed when the predicate returns true. It's given `None` when the handshake
+    /// had no `Origin`.
+    Custom(Arc<OriginPredicate>),
+}
+
+impl OriginPolicy {
+    /// Allows the origins matching `patterns`. A pattern is either an origin such as
+    /// `https://example.com`, or one with a wildcard for the subdomain such as
+    /// `https://*.example.com`, which matches `https://chat.example.com` but not
+    /// `https://example.com`. Origins are compared without regard to case.
+    pub fn allow<I, S>(patterns: I) -> OriginPolicy
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        OriginPolicy::Allow(patterns.into_iter().map(Into::into).collect())
+    }
+
+    pub fn custom<F>(predicate: F) -> OriginPolicy
+    where
+        F: Fn(Option<&str>) -> bool + Send + Sync + 'static,
+    {
+        OriginPolicy::Custom(Arc::new(predicate))
+    }
+
+    /// Whether a handshake with `origin` is allowed
+    pub fn allows(&self, origin: Option<&str>) -> bool {
+        match (self, origin) {
+            (OriginPolicy::Any, _) => true,
+            (OriginPolicy::Allow(patterns), Some(origin)) => patterns
+                .iter()
+                .any(|pattern| matches_pattern(pattern, origin)),
+            (OriginPolicy::Allow(_), None) => false,
+            (OriginPolicy::Custom(predicate), origin) => predicate(origin),
+        }
+    }
+}
+
+/// Whether `origin` matches a pattern of the kind described by [`OriginPolicy::allow`]
+fn matches_pattern(pattern: &str, origin: &str) -> bool {
+    let (scheme, host) = match pattern.split_once("://*.") {
+        Some(split) => split,
+        None => return pattern.eq_ignore_ascii_case(origin),
+    };
+    let rest = match origin.split_once("://") {
+        Some((origin_scheme, rest)) if origin_scheme.eq_ignore_ascii_case(scheme) => rest,
+        _ => return false,
+    };
+    // The subdomain has to be there, and the match has to fall on a dot so that
+    // `evilexample.com` doesn't match `*.example.com`
+    match rest.len().checked_sub(host.len() + 1) {
+        Some(dot) if dot > 0 => {
+            rest.as_bytes()[dot] == b'.'
+                && rest[dot + 1..].eq_ignore_ascii_case(host)
+                && !rest[..dot].contains(['/', ':', '@'])
+        }
+        _ => false,
+    }
+}
+
+impl Debug for OriginPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OriginPolicy::Any => write!(f, "Any"),
+            OriginPolicy::Allow(patterns) => f.debug_tuple("Allow").field(patterns).finish(),
+            OriginPolicy::Custom(_) => write!(f, "Custom(..)"),
+        }
+    }
+}
+
+/// Custom policies are only equal if they share the same predicate
+impl PartialEq for OriginPolicy {
+    fn eq(&self, other: &OriginPolicy) -> bool {
+        match (self, other) {
+            (OriginPolicy::Any, OriginPolicy::Any) => true,
+            (OriginPolicy::Allow(patterns), OriginPolicy::Allow(other)) => patterns == other,
+            (OriginPolicy::Custom(predicate), OriginPolicy::Custom(other)) => {
+                Arc::ptr_eq(predicate, other)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Eq for OriginPolicy {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allowed_origins_match_exactly_or_by_subdomain() {
+        let policy = OriginPolicy::allow(["https://example.com", "https://*.example.org:8443"]);
+
+        assert!(policy.allows(Some("https://example.com")));
+        assert!(policy.allows(Some("HTTPS://Example.COM")));
+        assert!(policy.allows(Some("https://chat.example.org:8443")));
+        assert!(policy.allows(Some("https://a.b.example.org:8443")));
+
+        for origin in [
+            "http://example.com",
+            "https://example.com.evil.net",
+            "https://chat.example.com",
+            "https://example.org:8443",
+            "https://evilexample.org:8443",
+            "https://chat.example.org",
+            "http://chat.example.org:8443",
+            "null",
+        ] {
+            assert!(!policy.allows(Some(origin)), "{origin:?}");
+        }
+        assert!(!policy.allows(None));
+    }
+
+    #[test]
+    fn custom_predicates_decide_for_themselves() {
+        let policy = OriginPolicy::custom(|origin| origin.is_none_or(|o| o.ends_with(".test")));
+
+        assert!(policy.allows(None));
+        assert!(policy.allows(Some("http://app.test")));
+        assert!(!policy.allows(Some("http://app.example")));
+        assert!(OriginPolicy::Any.allows(None));
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+
+use super::handler::Routes;
+use super::Handler;
+use crate::http::{HttpRequest, Uri};
+
+/// Serves different paths with different handlers, so that several endpoints can share a port.
+/// A path segment starting with `:` is a parameter that matches any one segment, which the
+/// handler can get from [`Connection::param`](super::Connection::param). Paths are matched and
+/// parameters taken after percent-decoding each segment. Routes are tried in the
+/// order they were added and handshakes for paths that match none of them get a 404. A route's
+/// handler can itself route, such as [`VirtualHosts`](super::VirtualHosts), and another `Router`
+/// can be [nested](Router::nest) under a prefix.
+///
+/// ```no_run
+/// # use tarnished_sockets::server::{Handler, Router, Server};
+/// # struct Chat;
+/// # impl Handler for Chat {}
+/// # struct Room;
+/// # impl Handler for Room {}
+/// let rooms = Router::new().route("/", Room).route("/users/:user", Room);
+/// let router = Router::new().route("/chat", Chat).nest("/rooms/:id", rooms);
+/// Server::new(router).listen("127.0.0.1:7878").unwrap();
+/// ```
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+struct Route {
+    segments: Vec<Segment>,
+    handler: Box<dyn Routes>,
+    /// Whether the route matches paths that start with its segments and hands the handler the
+    /// rest of the path, rather than matching whole paths
+    nested: bool,
+}
+
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router { routes: Vec::new() }
+    }
+
+    /// Serves paths matching `pattern` with `handler`
+    pub fn route<H: Handler>(mut self, pattern: &str, handler: H) -> Router {
+        self.routes.push(Route {
+            segments: parse_pattern(pattern),
+            handler: Box::new(handler),
+            nested: false,
+        });
+        self
+    }
+
+    /// Serves paths starting with `prefix` with `handler`, which routes on what's left of the path
+    /// once the prefix is taken off. A path that is just the prefix is left with `/`. Parameters
+    /// in the prefix are kept alongside any the handler's own routes capture.
+    pub fn nest<H: Handler>(mut self, prefix: &str, handler: H) -> Router {
+        let mut segments = parse_pattern(prefix.trim_end_matches('/'));
+        // Nesting under `/` takes nothing off the path
+        if let [Segment::Literal(literal)] = &segments[..] {
+            if literal.is_empty() {
+                segments.clear();
+            }
+        }
+        self.routes.push(Route {
+            segments,
+            handler: Box::new(handler),
+            nested: true,
+        });
+        self
+    }
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    pattern
+        .strip_prefix('/')
+        .unwrap_or(pattern)
+        .split('/')
+        .map(|segment| match segment.strip_prefix(':') {
+            Some(name) => Segment::Param(name.to_string()),
+            None => Segment::Literal(segment.to_string()),
+        })
+        .collect()
+}
+
+impl Route {
+    /// The parameters in `uri`, if its path matches this route
+    fn matches(&self, uri: &Uri) -> Option<HashMap<String, String>> {
+        let length = uri.segments().len();
+        if length < self.segments.len() || !self.nested && length != self.segments.len() {
+            return None;
+        }
+
+        let mut params = HashMap::new();
+        for (expected, segment) in self.segments.iter().zip(uri.segments()) {
+            match expected {
+                Segment::Literal(literal) if literal == segment => {}
+                Segment::Param(name) if !segment.is_empty() => {
+                    params.insert(name.clone(), segment.clone());
+                }
+                _ => return None,
+            }
+        }
+        Some(params)
+    }
+}
+
+impl Handler for Router {
+    fn handler_for<'a>(
+        &'a self,
+        request: &HttpRequest,
+        params: &mut HashMap<String, String>,
+    ) -> Option<&'a dyn Handler> {
+        self.routes.iter().find_map(|route| {
+            let mut matched = params.clone();
+            matched.extend(route.matches(&request.uri)?);
+            let handler = if route.nested {
+                let request = HttpRequest {
+                    uri: request.uri.strip_segments(route.segments.len()),
+                    ..request.clone()
+                };
+                route.handler.route(&request, &mut matched)?
+            } else {
+                route.handler.route(request, &mut matched)?
+            };
+            *params = matched;
+            Some(handler)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{HeaderMap, HttpMethod, HttpVersion};
+
+    /// Not zero sized, so that every boxed one has its own address
+    struct Nothing(#[allow(dead_code)] u8);
+
+    impl Handler for Nothing {}
+
+    fn request(path: &str) -> HttpRequest {
+        HttpRequest {
+            method: HttpMethod::GET,
+            uri: Uri::parse(path).unwrap(),
+            http_version: HttpVersion::HTTP_1_1,
+            headers: HeaderMap::new(),
+        }
+    }
+
+    fn params(router: &Router, path: &str) -> Option<Vec<(String, String)>> {
+        let mut params = HashMap::new();
+        router.handler_for(&request(path), &mut params)?;
+        let mut params: Vec<(String, String)> = params.into_iter().collect();
+        params.sort();
+        Some(params)
+    }
+
+    #[test]
+    fn literal_paths_match_exactly() {
+        let router = Router::new().route("/chat", Nothing(0));
+
+        assert_eq!(params(&router, "/chat"), Some(vec![]));
+        assert_eq!(params(&router, "/chat/"), None);
+        assert_eq!(params(&router, "/chats"), None);
+        assert_eq!(params(&router, "/"), None);
+    }
+
+    #[test]
+    fn parameters_are_captured() {
+        let router = Router::new()
+            .route("/rooms/:id", Nothing(0))
+            .route("/rooms/:id/users/:user", Nothing(0));
+
+        assert_eq!(
+            params(&router, "/rooms/7"),
+            Some(vec![("id".to_string(), "7".to_string())])
+        );
+        assert_eq!(
+            params(&router, "/rooms/7/users/ann"),
+            Some(vec![
+                ("id".to_string(), "7".to_string()),
+                ("user".to_string(), "ann".to_string())
+            ])
+        );
+        assert_eq!(
+            params(&router, "/rooms/caf%C3%A9"),
+            Some(vec![("id".to_string(), "café".to_string())])
+        );
+        assert_eq!(params(&router, "/rooms/"), None);
+        assert_eq!(params(&router, "/rooms/7/users"), None);
+    }
+
+    #[test]
+    fn nested_routers_route_on_the_rest_of_the_path() {
+        let inner = Router::new()
+            .route("/", Nothing(0))
+            .route("/chat", Nothing(0));
+        let index = &*inner.routes[0].handler as *const dyn Routes as *const ();
+        let chat = &*inner.routes[1].handler as *const dyn Routes as *const ();
+        let router = Router::new().nest("/api", inner);
+        let route = |path: &str| {
+            let handler = router.handler_for(&request(path), &mut HashMap::new())?;
+            Some(handler as *const dyn Handler as *const ())
+        };
+
+        assert_eq!(route("/api/chat"), Some(chat));
+        assert_eq!(route("/api"), Some(index));
+        assert_eq!(route("/api/"), Some(index));
+        assert_eq!(route("/chat"), None);
+        assert_eq!(route("/api/news"), None);
+    }
+
+    #[test]
+    fn nested_routes_keep_the_outer_parameters() {
+        let users = Router::new().route("/users/:user", Nothing(0));
+        let rooms = Router::new().nest("/rooms/:id", users);
+        let router = Router::new().nest("/sites/:site", rooms);
+
+        assert_eq!(
+            params(&router, "/sites/home/rooms/7/users/ann"),
+            Some(vec![
+                ("id".to_string(), "7".to_string()),
+                ("site".to_string(), "home".to_string()),
+                ("user".to_string(), "ann".to_string())
+            ])
+        );
+        assert_eq!(params(&router, "/sites/home/rooms/7"), None);
+        assert_eq!(params(&router, "/rooms/7/users/ann"), None);
+    }
+
+    #[test]
+    fn parameters_from_routes_that_fail_are_dropped() {
+        let inner = Router::new().route("/chat", Nothing(0));
+        let router = Router::new()
+            .nest("/:tenant", inner)
+            .route("/:id/news", Nothing(0));
+
+        assert_eq!(
+            params(&router, "/7/news"),
+            Some(vec![("id".to_string(), "7".to_string())])
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+
+use super::handler::Routes;
+use super::Handler;
+use crate::http::HttpRequest;
+
+/// Serves different hosts with different handlers, so that several sites can share a listener.
+/// Each host's handler can itself be a [`Router`](super::Router). Host names are compared without
+/// regard to case or port, and handshakes for hosts that aren't listed go to the fallback, or get
+/// a 404 if there isn't one.
+///
+/// ```no_run
+/// # use tarnished_sockets::server::{Handler, Router, Server, VirtualHosts};
+/// # struct Chat;
+/// # impl Handler for Chat {}
+/// let hosts = VirtualHosts::new()
+///     .host("example.com", Router::new().route("/chat", Chat))
+///     .host("staging.example.com", Router::new().route("/chat", Chat));
+/// Server::new(hosts).listen("127.0.0.1:7878").unwrap();
+/// ```
+#[derive(Default)]
+pub struct VirtualHosts {
+    hosts: Vec<(String, Box<dyn Routes>)>,
+    fallback: Option<Box<dyn Routes>>,
+}
+
+impl VirtualHosts {
+    pub fn new() -> VirtualHosts {
+        VirtualHosts::default()
+    }
+
+    /// Serves handshakes for `name` with `handler`
+    pub fn host<H: Handler>(mut self, name: &str, handler: H) -> VirtualHosts {
+        self.hosts.push((name.to_string(), Box::new(handler)));
+        self
+    }
+
+    /// Serves handshakes for every host that isn't listed with `handler`
+    pub fn fallback<H: Handler>(mut self, handler: H) -> VirtualHosts {
+        self.fallback = Some(Box::new(handler));
+        self
+    }
+}
+
+impl Handler for VirtualHosts {
+    fn handler_for<'a>(
+        &'a self,
+        request: &HttpRequest,
+        params: &mut HashMap<String, String>,
+    ) -> Option<&'a dyn Handler> {
+        let host = request.host().unwrap_or_default();
+        let handler = self
+            .hosts
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(host))
+            .map(|(_, handler)| handler)
+            .or(self.fallback.as_ref())?;
+        handler.route(request, params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Router;
+
+    /// Not zero sized, so that every boxed one has its own address
+    struct Nothing(#[allow(dead_code)] u8);
+
+    impl Handler for Nothing {}
+
+    /// The address of the handler a handshake for `host` and `path` is routed to
+    fn route(hosts: &VirtualHosts, host: &str, path: &str) -> Option<*const ()> {
+        let request = format!("GET {path} HTTP/1.1\r\nHost: {host}\r\n\r\n");
+        let request = HttpRequest::build(&mut request.as_bytes()).unwrap();
+        let handler = hosts.handler_for(&request, &mut HashMap::new())?;
+        Some(handler as *const dyn Handler as *const ())
+    }
+
+    #[test]
+    fn hosts_are_served_by_their_own_handlers() {
+        let hosts = VirtualHosts::new()
+            .host("example.com", Router::new().route("/chat", Nothing(0)))
+            .host("staging.example.com", Nothing(0));
+
+        let prod = route(&hosts, "example.com", "/chat");
+        assert!(prod.is_some());
+        assert_eq!(route(&hosts, "EXAMPLE.COM:443", "/chat"), prod);
+        assert_eq!(route(&hosts, "example.com", "/news"), None);
+
+        let staging = route(&hosts, "staging.example.com", "/news");
+        assert!(staging.is_some() && staging != prod);
+        assert_eq!(route(&hosts, "other.com", "/chat"), None);
+    }
+
+    #[test]
+    fn unlisted_hosts_go_to_the_fallback() {
+        let hosts = VirtualHosts::new()
+            .host("example.com", Nothing(0))
+            .fallback(Nothing(0));
+
+        let fallback = route(&hosts, "other.com", "/");
+        assert!(fallback.is_some() && fallback != route(&hosts, "example.com", "/"));
+        assert_eq!(route(&hosts, "another.com", "/chat"), fallback);
+    }
+}
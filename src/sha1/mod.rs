@@ -94,6 +94,7 @@ pub fn hash(message: &str) -> Vec<u8> {
 }
 
 #[cfg(test)]
+#[allow(clippy::get_first, clippy::unnecessary_cast)]
 mod tests {
     use super::*;
 
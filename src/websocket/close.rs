@@ -0,0 +1,170 @@
+use super::{DataFrame, OpCode, WebSocketError};
+
+/// The largest reason that fits in a control frame alongside the two byte status code
+pub const MAX_CLOSE_REASON_LENGTH: usize = 123;
+
+/// Status codes sent in close frames, as defined in RFC 6455 section 7.4
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCode {
+    Normal,             // 1000
+    GoingAway,          // 1001
+    ProtocolError,      // 1002
+    UnsupportedData,    // 1003
+    Reserved,           // 1004
+    NoStatusReceived,   // 1005
+    Abnormal,           // 1006
+    InvalidPayload,     // 1007
+    PolicyViolation,    // 1008
+    MessageTooBig,      // 1009
+    MandatoryExtension, // 1010
+    InternalError,      // 1011
+    ServiceRestart,     // 1012
+    TryAgainLater,      // 1013
+    BadGateway,         // 1014
+    TlsHandshake,       // 1015
+    /// 3000-3999, registered with IANA for use by libraries and frameworks
+    Library(u16),
+    /// 4000-4999, for private use by applications
+    Application(u16),
+}
+
+impl CloseCode {
+    /// Some codes are only used to report a close locally and must never be sent in a close
+    /// frame
+    pub fn is_sendable(&self) -> bool {
+        !matches!(
+            self,
+            CloseCode::Reserved
+                | CloseCode::NoStatusReceived
+                | CloseCode::Abnormal
+                | CloseCode::TlsHandshake
+        )
+    }
+}
+
+impl TryFrom<u16> for CloseCode {
+    type Error = WebSocketError;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            1000 => Ok(CloseCode::Normal),
+            1001 => Ok(CloseCode::GoingAway),
+            1002 => Ok(CloseCode::ProtocolError),
+            1003 => Ok(CloseCode::UnsupportedData),
+            1004 => Ok(CloseCode::Reserved),
+            1005 => Ok(CloseCode::NoStatusReceived),
+            1006 => Ok(CloseCode::Abnormal),
+            1007 => Ok(CloseCode::InvalidPayload),
+            1008 => Ok(CloseCode::PolicyViolation),
+            1009 => Ok(CloseCode::MessageTooBig),
+            1010 => Ok(CloseCode::MandatoryExtension),
+            1011 => Ok(CloseCode::InternalError),
+            1012 => Ok(CloseCode::ServiceRestart),
+            1013 => Ok(CloseCode::TryAgainLater),
+            1014 => Ok(CloseCode::BadGateway),
+            1015 => Ok(CloseCode::TlsHandshake),
+            3000..=3999 => Ok(CloseCode::Library(value)),
+            4000..=4999 => Ok(CloseCode::Application(value)),
+            code => Err(WebSocketError::InvalidCloseCode(code)),
+        }
+    }
+}
+
+impl From<CloseCode> for u16 {
+    fn from(value: CloseCode) -> Self {
+        match value {
+            CloseCode::Normal => 1000,
+            CloseCode::GoingAway => 1001,
+            CloseCode::ProtocolError => 1002,
+            CloseCode::UnsupportedData => 1003,
+            CloseCode::Reserved => 1004,
+            CloseCode::NoStatusReceived => 1005,
+            CloseCode::Abnormal => 1006,
+            CloseCode::InvalidPayload => 1007,
+            CloseCode::PolicyViolation => 1008,
+            CloseCode::MessageTooBig => 1009,
+            CloseCode::MandatoryExtension => 1010,
+            CloseCode::InternalError => 1011,
+            CloseCode::ServiceRestart => 1012,
+            CloseCode::TryAgainLater => 1013,
+            CloseCode::BadGateway => 1014,
+            CloseCode::TlsHandshake => 1015,
+            CloseCode::Library(code) | CloseCode::Application(code) => code,
+        }
+    }
+}
+
+/// The status code and reason carried by a close frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: CloseCode,
+    pub reason: String,
+}
+
+impl CloseFrame {
+    /// Parses the payload of a close frame. A close frame may have no body at all, in which case
+    /// there is no status code either.
+    pub fn parse(payload: &[u8]) -> Result<Option<CloseFrame>, WebSocketError> {
+        match payload {
+            [] => Ok(None),
+            [_] => Err(WebSocketError::BadClosePayload),
+            [high, low, reason @ ..] => {
+                let code = CloseCode::try_from(u16::from_be_bytes([*high, *low]))?;
+                if !code.is_sendable() {
+                    return Err(WebSocketError::InvalidCloseCode(code.into()));
+                }
+                let reason = std::str::from_utf8(reason)
+                    .map_err(|_| WebSocketError::InvalidUtf8)?
+                    .to_string();
+                Ok(Some(CloseFrame { code, reason }))
+            }
+        }
+    }
+
+    pub fn to_dataframe(&self) -> DataFrame {
+        let mut payload = u16::from(self.code).to_be_bytes().to_vec();
+        payload.extend(self.reason.as_bytes());
+        DataFrame::new(OpCode::Close, payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn close_code_round_trips() {
+        for code in [1000, 1001, 1011, 1015, 3000, 4999] {
+            assert_eq!(u16::from(CloseCode::try_from(code).unwrap()), code);
+        }
+    }
+
+    #[test]
+    fn close_code_rejects_unassigned_codes() {
+        for code in [0, 999, 1016, 2999, 5000] {
+            assert!(matches!(
+                CloseCode::try_from(code),
+                Err(WebSocketError::InvalidCloseCode(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn parse_close_frame_works() {
+        let parsed = CloseFrame::parse(&[0x03, 0xE8, b'b', b'y', b'e']).unwrap();
+        let expected = CloseFrame {
+            code: CloseCode::Normal,
+            reason: "bye".into(),
+        };
+
+        assert_eq!(parsed, Some(expected));
+        assert_eq!(CloseFrame::parse(&[]).unwrap(), None);
+    }
+
+    #[test]
+    fn parse_close_frame_rejects_local_only_codes() {
+        // 1005 is only ever reported locally
+        assert!(CloseFrame::parse(&[0x03, 0xED]).is_err());
+        assert!(CloseFrame::parse(&[0x03]).is_err());
+    }
+}
@@ -0,0 +1,357 @@
+use super::{
+    DataFrame, OpCode, RsvBits, WebSocketConfig, WebSocketError, MAX_CONTROL_PAYLOAD_LENGTH,
+};
+
+/// Turns bytes into dataframes without doing any IO itself. Bytes can be fed in slices of any
+/// size, and frames come out once all of their bytes have arrived.
+///
+/// Frame headers are validated and checked against the size limits as soon as they're complete,
+/// before any of the payload is buffered.
+#[derive(Debug)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    max_frame_size: u64,
+    max_message_size: u64,
+    /// Payload decoded so far for the data message in progress
+    message_size: u64,
+    /// The reserved bits negotiated extensions have given a meaning to
+    allowed_rsv: RsvBits,
+}
+
+/// The part of a frame header up to and including the payload length, which is everything needed
+/// to validate the frame
+struct FrameHeader {
+    fin: bool,
+    rsv1: bool,
+    rsv2: bool,
+    rsv3: bool,
+    opcode: OpCode,
+    mask: bool,
+    payload_length: u64,
+    /// How many bytes the whole header takes up, including the mask key
+    length: usize,
+}
+
+impl FrameDecoder {
+    pub fn new(config: &WebSocketConfig) -> FrameDecoder {
+        FrameDecoder {
+            buffer: Vec::new(),
+            max_frame_size: config.max_frame_size,
+            max_message_size: config.max_message_size,
+            message_size: 0,
+            allowed_rsv: RsvBits::NONE,
+        }
+    }
+
+    /// Lets incoming frames set the given reserved bits, once an extension that uses them has
+    /// been negotiated. Frames setting any other reserved bit are still an error.
+    pub fn allow_rsv(&mut self, bits: RsvBits) {
+        self.allowed_rsv = bits;
+    }
+
+    /// Adds bytes to the end of those waiting to be decoded
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// How many bytes are waiting for the rest of their frame
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Decodes the next frame, or returns `None` if its bytes haven't all arrived yet. Any error
+    /// means the stream can't be decoded any further.
+    pub fn decode(&mut self) -> Result<Option<DataFrame>, WebSocketError> {
+        let header = match self.decode_header()? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+
+        validate_header(&header, self.allowed_rsv)?;
+
+        // Check the limits before waiting on any of the payload, the length can't be trusted
+        let message_size = match header.opcode {
+            OpCode::Continuation => self.message_size,
+            _ => 0,
+        };
+        if header.payload_length > self.max_frame_size
+            || message_size + header.payload_length > self.max_message_size
+        {
+            return Err(WebSocketError::PayloadTooLarge);
+        }
+
+        // Fits in a usize now that it's within the limits, unless those are set beyond what the
+        // platform can address
+        let frame_length = usize::try_from(header.payload_length)
+            .ok()
+            .and_then(|length| length.checked_add(header.length))
+            .ok_or(WebSocketError::PayloadTooLarge)?;
+        if self.buffer.len() < frame_length {
+            return Ok(None);
+        }
+
+        let mut mask_key: [u8; 4] = [0; 4];
+        mask_key.copy_from_slice(&self.buffer[header.length - 4..header.length]);
+
+        let mut payload: Vec<u8> = self
+            .buffer
+            .drain(..frame_length)
+            .skip(header.length)
+            .collect();
+        payload
+            .iter_mut()
+            .enumerate()
+            .for_each(|(index, byte)| *byte ^= mask_key[index % 4]);
+
+        if !header.opcode.is_control() {
+            self.message_size = match header.fin {
+                true => 0,
+                false => message_size + header.payload_length,
+            };
+        }
+
+        Ok(Some(DataFrame {
+            fin: header.fin,
+            rsv1: header.rsv1,
+            rsv2: header.rsv2,
+            rsv3: header.rsv3,
+            opcode: header.opcode,
+            mask: header.mask,
+            mask_key,
+            payload_length: header.payload_length,
+            payload,
+        }))
+    }
+
+    fn decode_header(&self) -> Result<Option<FrameHeader>, WebSocketError> {
+        let (byte, length_byte) = match self.buffer[..] {
+            [byte, length_byte, ..] => (byte, length_byte),
+            _ => return Ok(None),
+        };
+
+        let (fin, rsv1, rsv2, rsv3, opcode) = (
+            bit(byte, 7),
+            bit(byte, 6),
+            bit(byte, 5),
+            bit(byte, 4),
+            OpCode::try_from(byte & 0x0F)?,
+        );
+
+        // handle message length parsing
+        let (mask, payload_length) = (bit(length_byte, 7), length_byte & 0x7F);
+        if !mask {
+            return Err(WebSocketError::UnencodedMessage);
+        }
+
+        let length_bytes = match payload_length {
+            0..=125 => 0,
+            126 => 2,
+            127 => 8,
+            _ => panic!("Found a payload length value that is impossible"),
+        };
+        if self.buffer.len() < 2 + length_bytes {
+            return Ok(None);
+        }
+
+        let extended_length = &self.buffer[2..2 + length_bytes];
+        let payload_length = match payload_length {
+            0..=125 => payload_length as u64,
+            126 => u16::from_be_bytes([extended_length[0], extended_length[1]]) as u64,
+            _ => {
+                // The most significant bit cannot be 1
+                if bit(extended_length[0], 7) {
+                    return Err(WebSocketError::BadPayloadLength);
+                }
+                u64::from_be_bytes(extended_length.try_into().unwrap())
+            }
+        };
+
+        Ok(Some(FrameHeader {
+            fin,
+            rsv1,
+            rsv2,
+            rsv3,
+            opcode,
+            mask,
+            payload_length,
+            length: 2 + length_bytes + 4,
+        }))
+    }
+}
+
+/// Turns dataframes into bytes without doing any IO itself
+#[derive(Debug, Default)]
+pub struct FrameEncoder;
+
+impl FrameEncoder {
+    pub fn new() -> FrameEncoder {
+        FrameEncoder
+    }
+
+    /// Appends the encoded frame to `buffer`. The payload length uses the smallest of the 7, 16
+    /// or 64 bit forms that fits, and the payload is never masked since servers must not mask
+    /// their frames.
+    pub fn encode(&mut self, frame: &DataFrame, buffer: &mut Vec<u8>) {
+        let length = frame.payload.len();
+        buffer.reserve(length + 10);
+
+        buffer.push(
+            (frame.fin as u8) << 7
+                | (frame.rsv1 as u8) << 6
+                | (frame.rsv2 as u8) << 5
+                | (frame.rsv3 as u8) << 4
+                | frame.opcode as u8,
+        );
+
+        match length {
+            0..=125 => buffer.push(length as u8),
+            126..=0xFFFF => {
+                buffer.push(126);
+                buffer.extend((length as u16).to_be_bytes());
+            }
+            _ => {
+                buffer.push(127);
+                buffer.extend((length as u64).to_be_bytes());
+            }
+        }
+
+        buffer.extend(&frame.payload);
+    }
+}
+
+/// Checks the header of an incoming frame against the rules of RFC 6455 section 5, any frame that
+/// breaks them has to fail the connection
+fn validate_header(header: &FrameHeader, allowed_rsv: RsvBits) -> Result<(), WebSocketError> {
+    // Only the reserved bits of negotiated extensions have a meaning
+    let rsv = RsvBits {
+        rsv1: header.rsv1,
+        rsv2: header.rsv2,
+        rsv3: header.rsv3,
+    };
+    if !rsv.is_within(allowed_rsv) {
+        return Err(WebSocketError::ReservedBitsSet);
+    }
+
+    if header.opcode.is_control() {
+        if !header.fin {
+            return Err(WebSocketError::FragmentedControlFrame);
+        }
+        if header.payload_length > MAX_CONTROL_PAYLOAD_LENGTH as u64 {
+            return Err(WebSocketError::ControlFrameTooLarge);
+        }
+    }
+
+    Ok(())
+}
+
+/// Gets the bit at position `position`. Positions are assumed to be big endian, so the 7th
+/// position is the most significant bit
+fn bit(byte: u8, position: u8) -> bool {
+    ((byte >> position) & 1) != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Masked "Hello" from RFC 6455 section 5.7
+    const HELLO: [u8; 11] = [
+        0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+    ];
+
+    #[test]
+    fn decoding_byte_by_byte_works() {
+        let mut decoder = FrameDecoder::new(&WebSocketConfig::default());
+
+        for byte in &HELLO[..HELLO.len() - 1] {
+            decoder.extend(&[*byte]);
+            assert!(decoder.decode().unwrap().is_none());
+        }
+        decoder.extend(&HELLO[HELLO.len() - 1..]);
+
+        let frame = decoder.decode().unwrap().unwrap();
+        assert_eq!(frame.opcode, OpCode::Text);
+        assert_eq!(frame.payload, b"Hello");
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn decoding_several_frames_from_one_slice_works() {
+        let mut decoder = FrameDecoder::new(&WebSocketConfig::default());
+        decoder.extend(&[HELLO, HELLO].concat());
+        decoder.extend(&HELLO[..3]);
+
+        assert_eq!(decoder.decode().unwrap().unwrap().payload, b"Hello");
+        assert_eq!(decoder.decode().unwrap().unwrap().payload, b"Hello");
+        assert!(decoder.decode().unwrap().is_none());
+        assert_eq!(decoder.buffered(), 3);
+    }
+
+    #[test]
+    fn decoding_checks_limits_before_payload_arrives() {
+        let mut decoder = FrameDecoder::new(&WebSocketConfig {
+            max_frame_size: 1024,
+            ..Default::default()
+        });
+        // A 64 bit length claiming 4GiB, with nothing after it
+        decoder.extend(&[0x82, 0xFF, 0, 0, 0, 1, 0, 0, 0, 0]);
+
+        assert!(matches!(
+            decoder.decode(),
+            Err(WebSocketError::PayloadTooLarge)
+        ));
+    }
+
+    #[test]
+    fn decoding_tracks_message_size_across_fragments() {
+        let mut decoder = FrameDecoder::new(&WebSocketConfig {
+            max_message_size: 6,
+            ..Default::default()
+        });
+        decoder.extend(&[0x01, 0x83, 0, 0, 0, 0, b'a', b'b', b'c']);
+        decoder.extend(&[0x00, 0x84, 0, 0, 0, 0]);
+
+        assert!(decoder.decode().unwrap().is_some());
+        assert!(matches!(
+            decoder.decode(),
+            Err(WebSocketError::PayloadTooLarge)
+        ));
+    }
+
+    #[test]
+    fn only_allowed_reserved_bits_can_be_set() {
+        // "Hello" with RSV1 set
+        let mut frame = HELLO;
+        frame[0] |= 0x40;
+
+        let mut decoder = FrameDecoder::new(&WebSocketConfig::default());
+        decoder.extend(&frame);
+        assert!(matches!(
+            decoder.decode(),
+            Err(WebSocketError::ReservedBitsSet)
+        ));
+
+        let mut decoder = FrameDecoder::new(&WebSocketConfig::default());
+        decoder.allow_rsv(RsvBits {
+            rsv1: true,
+            ..RsvBits::NONE
+        });
+        decoder.extend(&frame);
+        assert!(decoder.decode().unwrap().unwrap().rsv1);
+
+        frame[0] |= 0x20;
+        decoder.extend(&frame);
+        assert!(matches!(
+            decoder.decode(),
+            Err(WebSocketError::ReservedBitsSet)
+        ));
+    }
+
+    #[test]
+    fn encoding_works() {
+        let mut buffer = vec![0xFF];
+        FrameEncoder::new().encode(&DataFrame::new(OpCode::Pong, b"ok".to_vec()), &mut buffer);
+
+        assert_eq!(buffer, [0xFF, 0x8A, 0x02, b'o', b'k']);
+    }
+}
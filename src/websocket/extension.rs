@@ -0,0 +1,131 @@
+use std::fmt::{Debug, Display};
+use std::sync::Arc;
+
+use super::{DataFrame, WebSocketError};
+
+/// A protocol extension negotiated in the opening handshake through `Sec-WebSocket-Extensions`,
+/// such as compression. Each connection gets its own instance, made by an [`ExtensionFactory`],
+/// so an extension can keep state for the connection it belongs to.
+///
+/// Every frame read passes through [`decode`](Extension::decode) before it's put together into a
+/// message, and every frame written passes through [`encode`](Extension::encode) on the way out.
+/// With several extensions, frames are encoded by each in the order they were negotiated and
+/// decoded in the reverse order. The reserved bits an extension claims are allowed on incoming
+/// frames, and it's up to the extension to clear them as it decodes. Decoded frames and the
+/// messages they make up are held to the connection's size limits, however large the extension
+/// has made them.
+pub trait Extension: Send + Debug {
+    /// The token the extension is offered with, such as `permessage-deflate`
+    fn name(&self) -> &str;
+
+    /// The reserved bits in the frame header the extension gives a meaning to
+    fn rsv_bits(&self) -> RsvBits;
+
+    /// Considers an offer of the extension with the parameters the client gave it. Returns the
+    /// parameters to accept it with, or `None` to decline this offer. A client can offer an
+    /// extension more than once with different parameters, in which case the offers are tried in
+    /// the order they were made.
+    fn negotiate(&mut self, params: &[ExtensionParam]) -> Option<Vec<ExtensionParam>>;
+
+    /// Transforms a frame read from the peer
+    fn decode(&mut self, _frame: &mut DataFrame) -> Result<(), WebSocketError> {
+        Ok(())
+    }
+
+    /// Transforms a frame before it's written to the peer
+    fn encode(&mut self, _frame: &mut DataFrame) -> Result<(), WebSocketError> {
+        Ok(())
+    }
+}
+
+/// Some of the three reserved bits of a frame header
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RsvBits {
+    pub rsv1: bool,
+    pub rsv2: bool,
+    pub rsv3: bool,
+}
+
+impl RsvBits {
+    pub const NONE: RsvBits = RsvBits {
+        rsv1: false,
+        rsv2: false,
+        rsv3: false,
+    };
+
+    /// The bits set in either
+    pub fn union(self, other: RsvBits) -> RsvBits {
+        RsvBits {
+            rsv1: self.rsv1 || other.rsv1,
+            rsv2: self.rsv2 || other.rsv2,
+            rsv3: self.rsv3 || other.rsv3,
+        }
+    }
+
+    /// Whether any bit is set in both
+    pub fn intersects(self, other: RsvBits) -> bool {
+        (self.rsv1 && other.rsv1) || (self.rsv2 && other.rsv2) || (self.rsv3 && other.rsv3)
+    }
+
+    /// Whether every bit set in `self` is also set in `other`
+    pub fn is_within(self, other: RsvBits) -> bool {
+        self.union(other) == other
+    }
+}
+
+/// A parameter of an extension offer or response, like `client_max_window_bits=10`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtensionParam {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+impl ExtensionParam {
+    pub fn new(name: &str, value: Option<&str>) -> ExtensionParam {
+        ExtensionParam {
+            name: name.to_string(),
+            value: value.map(str::to_string),
+        }
+    }
+}
+
+impl Display for ExtensionParam {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "{}={}", self.name, value),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// Makes a new instance of an extension for each connection that might negotiate it
+#[derive(Clone)]
+pub struct ExtensionFactory(Arc<dyn Fn() -> Box<dyn Extension> + Send + Sync>);
+
+impl ExtensionFactory {
+    pub fn new<F>(make: F) -> ExtensionFactory
+    where
+        F: Fn() -> Box<dyn Extension> + Send + Sync + 'static,
+    {
+        ExtensionFactory(Arc::new(make))
+    }
+
+    pub fn make(&self) -> Box<dyn Extension> {
+        (self.0)()
+    }
+}
+
+impl Debug for ExtensionFactory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ExtensionFactory(..)")
+    }
+}
+
+/// Factories are only equal if they share the same function
+impl PartialEq for ExtensionFactory {
+    fn eq(&self, other: &ExtensionFactory) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for ExtensionFactory {}
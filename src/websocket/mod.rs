@@ -1,118 +1,492 @@
 use std::{
     error::Error,
     fmt::Display,
-    io::{BufReader, Read},
-    net::TcpStream,
+    io::{BufReader, ErrorKind, Read, Write},
+    time::{Duration, Instant},
 };
 
+mod close;
+mod codec;
+mod extension;
+mod shutdown;
+
+/// Control frames must fit their payload in the 7 bit length form
+pub const MAX_CONTROL_PAYLOAD_LENGTH: usize = 125;
+
+pub use close::{CloseCode, CloseFrame, MAX_CLOSE_REASON_LENGTH};
+pub use codec::{FrameDecoder, FrameEncoder};
+pub use extension::{Extension, ExtensionFactory, ExtensionParam, RsvBits};
+pub use shutdown::Shutdown;
+
+/// A websocket connection over any stream that can be read from and written to, most commonly a
+/// `TcpStream`. The stream's writing half is shut down once the connection is closed, and the
+/// stream itself is closed when the `WebSocket` is dropped.
+///
+/// Frames are decoded from a single buffer that lives as long as the connection, so bytes read
+/// ahead of the current frame are kept for the next one.
 #[derive(Debug)]
-pub struct WebSocket {
-    socket: TcpStream,
+pub struct WebSocket<S> {
+    socket: S,
+    decoder: FrameDecoder,
+    encoder: FrameEncoder,
     awaiting_pong: bool,
+    partial_message: Option<PartialMessage>,
+    state: State,
+    config: WebSocketConfig,
+    last_ping: Instant,
+    /// When our close frame was sent, if we started the closing handshake
+    close_sent: Instant,
+    /// The extensions negotiated for the connection, in the order they were negotiated
+    extensions: Vec<Box<dyn Extension>>,
+}
+
+/// Limits and timings for a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WebSocketConfig {
+    /// The largest payload a single frame may carry
+    pub max_frame_size: u64,
+    /// The largest payload a message may carry once all of its fragments are put together
+    pub max_message_size: u64,
+    pub heartbeat: Option<Heartbeat>,
+    /// How long to wait for the peer to answer a close frame we sent before giving up on it and
+    /// shutting the connection down
+    pub close_timeout: Duration,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig {
+            max_frame_size: 16 << 20,
+            max_message_size: 64 << 20,
+            heartbeat: None,
+            close_timeout: Duration::from_secs(5),
+        }
+    }
 }
 
-impl WebSocket {
-    pub fn new(socket: TcpStream) -> WebSocket {
+/// Keeps idle connections alive by sending a ping every `interval`. If the peer doesn't answer
+/// with a pong within `timeout` the connection is considered dead and is closed.
+///
+/// The heartbeat runs whenever [`WebSocket::read_message`] is called, so on an idle connection
+/// it relies on the stream having a read timeout that's shorter than `interval` and `timeout`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+/// Where the connection is in the closing handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Open,
+    /// We've sent a close frame and are waiting for the peer's reply
+    Closing,
+    Closed,
+}
+
+impl<S: Read + Write + Shutdown> WebSocket<S> {
+    pub fn new(socket: S) -> WebSocket<S> {
+        WebSocket::with_config(socket, WebSocketConfig::default())
+    }
+
+    pub fn with_config(socket: S, config: WebSocketConfig) -> WebSocket<S> {
         WebSocket {
             socket,
+            decoder: FrameDecoder::new(&config),
+            encoder: FrameEncoder::new(),
             awaiting_pong: false,
+            partial_message: None,
+            state: State::Open,
+            config,
+            last_ping: Instant::now(),
+            close_sent: Instant::now(),
+            extensions: Vec::new(),
         }
     }
 
-    pub fn read_dataframe(&mut self) -> Result<DataFrame, WebSocketError> {
-        let mut header_bytes: [u8; 2] = [0; 2];
-        self.socket.read_exact(&mut header_bytes)?;
-
-        let byte = header_bytes[0];
-        let (fin, rsv1, rsv2, rsv3, opcode) = (
-            bit(byte, 7),
-            bit(byte, 6),
-            bit(byte, 5),
-            bit(byte, 4),
-            OpCode::try_from(byte & 0x0F as u8)?,
-        );
+    /// Takes over a reader that has already been used, such as for parsing the HTTP handshake.
+    /// Whatever it has buffered, like a frame the client sent straight after the handshake, is
+    /// decoded before anything else from the stream.
+    pub fn from_reader(reader: BufReader<S>, config: WebSocketConfig) -> WebSocket<S> {
+        let buffered = reader.buffer().to_vec();
+        WebSocket::from_partially_read(reader.into_inner(), &buffered, config)
+    }
 
-        // handle message length parsing
-        let (mask, payload_length) = (bit(header_bytes[1], 7) as bool, header_bytes[1] & 0x7F);
-        if !mask {
-            return Err(WebSocketError::UnencodedMessage);
-        }
-
-        let payload_length = match payload_length {
-            0..=125 => payload_length as u64,
-            126 => {
-                let mut length_bytes: [u8; 2] = [0; 2];
-                self.socket.read_exact(&mut length_bytes)?;
-                ((length_bytes[0] as u64) << 8) + length_bytes[1] as u64
-            }
-            127 => {
-                let mut length_bytes: [u8; 8] = [0; 8];
-                self.socket.read_exact(&mut length_bytes)?;
-                // The most significant bit cannot be 1
-                if bit(length_bytes[0], 7) {
-                    return Err(WebSocketError::BadPayloadLength);
+    /// Like [`from_reader`](WebSocket::from_reader), for when the bytes read past the handshake
+    /// were kept somewhere other than a `BufReader`
+    pub fn from_partially_read(socket: S, read: &[u8], config: WebSocketConfig) -> WebSocket<S> {
+        let mut websocket = WebSocket::with_config(socket, config);
+        websocket.decoder.extend(read);
+        websocket
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.socket
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.socket
+    }
+
+    /// Gives back the stream. Any bytes that were read but haven't been decoded yet are lost.
+    pub fn into_inner(self) -> S {
+        self.socket
+    }
+
+    /// Sets the extensions negotiated for the connection, in the order they were negotiated. Their
+    /// reserved bits are allowed on incoming frames from then on, so this should be called before
+    /// anything is read.
+    pub fn set_extensions(&mut self, extensions: Vec<Box<dyn Extension>>) {
+        let rsv = extensions.iter().fold(RsvBits::NONE, |bits, extension| {
+            bits.union(extension.rsv_bits())
+        });
+        self.decoder.allow_rsv(rsv);
+        self.extensions = extensions;
+    }
+
+    pub fn extensions(&self) -> &[Box<dyn Extension>] {
+        &self.extensions
+    }
+
+    /// Turns the heartbeat on or off. Pings are only sent while waiting in
+    /// [`read_message`](WebSocket::read_message).
+    pub fn set_heartbeat(&mut self, heartbeat: Option<Heartbeat>) {
+        self.config.heartbeat = heartbeat;
+        self.last_ping = Instant::now();
+        self.awaiting_pong = false;
+    }
+
+    pub fn is_awaiting_pong(&self) -> bool {
+        self.awaiting_pong
+    }
+
+    /// Runs the closing handshake: sends a close frame and waits for the peer to reply with its
+    /// own. Any other messages that arrive in the meantime are discarded. Once this returns the
+    /// `WebSocket` should be dropped to close the stream.
+    ///
+    /// If the peer doesn't reply within the config's `close_timeout` the connection is shut down
+    /// and [`WebSocketError::CloseTimeout`] is returned. As with the heartbeat, on a blocking stream
+    /// this relies on the stream having a read timeout.
+    pub fn close(&mut self, code: CloseCode, reason: &str) -> Result<(), WebSocketError> {
+        self.send_close(code, reason)?;
+
+        loop {
+            match self.read_message() {
+                Ok(Message::Close(_)) => return Ok(()),
+                Ok(_) => continue,
+                Err(error) if error.is_timeout() => continue,
+                Err(error) => {
+                    self.shutdown();
+                    return Err(error);
                 }
-                u64::from_be_bytes(length_bytes)
             }
-            _ => panic!("Found a payload length value that is impossible"),
+        }
+    }
+
+    /// Starts the closing handshake by sending a close frame, without waiting for the reply.
+    /// Messages can still be read until [`read_message`](WebSocket::read_message) returns the
+    /// peer's close, which is what a nonblocking stream has to do instead of calling
+    /// [`close`](WebSocket::close).
+    pub fn send_close(&mut self, code: CloseCode, reason: &str) -> Result<(), WebSocketError> {
+        if !code.is_sendable() {
+            return Err(WebSocketError::InvalidCloseCode(code.into()));
+        }
+        if reason.len() > MAX_CLOSE_REASON_LENGTH {
+            return Err(WebSocketError::CloseReasonTooLong);
+        }
+
+        let frame = CloseFrame {
+            code,
+            reason: reason.to_string(),
         };
+        self.write_dataframe(&frame.to_dataframe())?;
+        self.state = State::Closing;
+        self.close_sent = Instant::now();
+        Ok(())
+    }
 
-        let mut mask_key: [u8; 4] = [0; 4];
-        self.socket.read_exact(&mut mask_key)?;
-
-        // TODO IMPORTANT
-        // right now, we assume that usize is of size u64. This isn't necessarily true on 32 bit
-        // systems. For now I'm creating a buffer with capacity that is a u64, but this would panic
-        // and fail on a 32 bit system. To correct this, i probably need to write a vector that
-        // can take larger sizes / not usize
-        // TODO check if there's a way to initialize a vector with all zeroes that isn't this
-        // macro? I'm assuming the macro is fine for now
-
-        //let mut payload = vec![0u8; payload_length.try_into().unwrap()];
-        //self.socket.read_exact(&mut payload)?; // Check that this actually reads the amount
-
-        //let payload = payload
-        //    .iter()
-        //    .enumerate()
-        //    .map(|(index, byte)| byte ^ mask_key[index % 4])
-        //    .collect();
-
-        let payload: Vec<u8> = BufReader::new(self.socket.by_ref())
-            .take(payload_length)
-            .bytes()
-            .enumerate()
-            // Unfortunately, bytes returns a Result<u8, E>. This means we have to do some uglier
-            // stuff
-            .map(|(index, byte)| -> Result<u8, WebSocketError> { Ok(byte? ^ mask_key[index % 4]) })
-            .collect::<Result<Vec<u8>, WebSocketError>>()?;
-
-        Ok(DataFrame {
-            fin,
-            rsv1,
-            rsv2,
-            rsv3,
-            opcode,
-            mask,
-            mask_key,
-            payload_length,
-            payload,
-        })
+    /// Reads dataframes until a complete message is available. Fragments of a message are
+    /// buffered until the frame with `fin` set arrives, while control frames that arrive in
+    /// between fragments are returned straight away.
+    ///
+    /// When the peer starts the closing handshake its close frame is echoed back before returning
+    /// [`Message::Close`], after which the `WebSocket` should be dropped to close the stream.
+    /// Pings are answered with a pong carrying the same payload before they're returned.
+    ///
+    /// Protocol errors fail the connection: a close frame with the matching status code is sent
+    /// and nothing more is read or written.
+    ///
+    /// If the stream has a read timeout or is nonblocking, an idle connection returns an error for
+    /// which [`WebSocketError::is_timeout`] is true. Nothing is lost and the call can be retried.
+    pub fn read_message(&mut self) -> Result<Message, WebSocketError> {
+        let result = self.next_message();
+        result.map_err(|error| self.fail(error))
+    }
+
+    fn next_message(&mut self) -> Result<Message, WebSocketError> {
+        if self.state == State::Closed {
+            return Err(WebSocketError::ConnectionClosed);
+        }
+
+        loop {
+            self.check_heartbeat()?;
+            self.check_close_timeout()?;
+            let df = self.next_dataframe()?;
+            let fin = df.fin;
+
+            match df.opcode {
+                OpCode::Ping => {
+                    if self.state == State::Open {
+                        self.send_pong(&df.payload)?;
+                    }
+                    return Ok(Message::Ping(df.payload));
+                }
+                OpCode::Pong => {
+                    self.awaiting_pong = false;
+                    return Ok(Message::Pong(df.payload));
+                }
+                OpCode::Close => return self.handle_close(&df.payload),
+                OpCode::Continuation => self
+                    .partial_message
+                    .as_mut()
+                    .ok_or(WebSocketError::UnexpectedContinuation)?
+                    .extend(df.payload, self.config.max_message_size)?,
+                OpCode::Text | OpCode::Binary => {
+                    if self.partial_message.is_some() {
+                        return Err(WebSocketError::UnfinishedMessage);
+                    }
+                    let mut partial = PartialMessage {
+                        opcode: df.opcode,
+                        payload: Vec::new(),
+                        utf8_checked: 0,
+                    };
+                    partial.extend(df.payload, self.config.max_message_size)?;
+                    self.partial_message = Some(partial);
+                }
+            }
+
+            if fin {
+                if let Some(partial) = self.partial_message.take() {
+                    return partial.into_message();
+                }
+            }
+        }
+    }
+
+    /// Sends a ping when one is due according to the heartbeat, and fails the connection if the
+    /// last one wasn't answered in time.
+    fn check_heartbeat(&mut self) -> Result<(), WebSocketError> {
+        let heartbeat = match self.config.heartbeat {
+            Some(heartbeat) if self.state == State::Open => heartbeat,
+            _ => return Ok(()),
+        };
+
+        let now = Instant::now();
+        if self.awaiting_pong {
+            if now >= self.last_ping + heartbeat.timeout {
+                return Err(WebSocketError::PongTimeout);
+            }
+        } else if now >= self.last_ping + heartbeat.interval {
+            self.send_ping(&[])?;
+            self.awaiting_pong = true;
+            self.last_ping = now;
+        }
+
+        Ok(())
+    }
+
+    /// Gives up on the closing handshake once the peer has taken longer than the close timeout to
+    /// answer our close frame
+    fn check_close_timeout(&mut self) -> Result<(), WebSocketError> {
+        if self.state == State::Closing && self.close_sent.elapsed() >= self.config.close_timeout {
+            self.shutdown();
+            return Err(WebSocketError::CloseTimeout);
+        }
+        Ok(())
+    }
+
+    fn handle_close(&mut self, payload: &[u8]) -> Result<Message, WebSocketError> {
+        let frame = CloseFrame::parse(payload)?;
+
+        if self.state == State::Open {
+            // Echo the status code back, a close without one is answered with an empty close
+            let reply = match &frame {
+                Some(frame) => CloseFrame {
+                    code: frame.code,
+                    reason: String::new(),
+                }
+                .to_dataframe(),
+                None => DataFrame::new(OpCode::Close, Vec::new()),
+            };
+            self.write_dataframe(&reply)?;
+        }
+
+        self.shutdown();
+        Ok(Message::Close(frame))
+    }
+
+    /// Fails the connection as described in RFC 6455 section 7.1.7. Errors with a status code
+    /// get a close frame sent with that code, and the stream is shut down without waiting for the
+    /// peer to reply since it can't be trusted to follow the protocol.
+    fn fail(&mut self, error: WebSocketError) -> WebSocketError {
+        if let Some(code) = error.close_code() {
+            if self.state == State::Open {
+                let frame = CloseFrame {
+                    code,
+                    reason: String::new(),
+                };
+                let _ = self.write_dataframe(&frame.to_dataframe());
+            }
+            self.shutdown();
+        }
+        error
+    }
+
+    /// Stops the connection once the closing handshake is over, shutting down the writing half of
+    /// the stream so the peer sees it end. The stream itself is closed when it's dropped.
+    fn shutdown(&mut self) {
+        self.state = State::Closed;
+        // The peer may already have closed its end, in which case there's nothing left to do
+        let _ = self.socket.flush();
+        let _ = self.socket.shutdown_write();
+    }
+
+    /// Reads a single dataframe, as decoded by any extensions. Frames that break the configured
+    /// limits, either as they arrive or once decoded, fail the connection.
+    pub fn read_dataframe(&mut self) -> Result<DataFrame, WebSocketError> {
+        let result = self.next_dataframe();
+        result.map_err(|error| self.fail(error))
+    }
+
+    fn next_dataframe(&mut self) -> Result<DataFrame, WebSocketError> {
+        let mut chunk = [0u8; 8192];
+
+        loop {
+            if let Some(mut frame) = self.decoder.decode()? {
+                for extension in self.extensions.iter_mut().rev() {
+                    extension.decode(&mut frame)?;
+                }
+                // The decoder only checked the size the frame had on the wire, which an extension
+                // such as compression can make far larger
+                if frame.payload.len() as u64 > self.config.max_frame_size {
+                    return Err(WebSocketError::PayloadTooLarge);
+                }
+                frame.payload_length = frame.payload.len() as u64;
+                return Ok(frame);
+            }
+
+            // Whatever was read stays in the decoder, so an error here never loses part of a frame
+            let read = match self.socket.read(&mut chunk) {
+                Ok(0) => return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into()),
+                Ok(read) => read,
+                Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error.into()),
+            };
+            self.decoder.extend(&chunk[..read]);
+        }
+    }
+
+    /// Writes a single dataframe to the socket, once any extensions have encoded it. See
+    /// [`FrameEncoder::encode`] for the encoding.
+    /// Nothing can be sent once a close frame has been sent.
+    pub fn write_dataframe(&mut self, dataframe: &DataFrame) -> Result<(), WebSocketError> {
+        if self.state != State::Open {
+            return Err(WebSocketError::ConnectionClosed);
+        }
+        if dataframe.opcode.is_control() && dataframe.payload.len() > MAX_CONTROL_PAYLOAD_LENGTH {
+            return Err(WebSocketError::ControlFrameTooLarge);
+        }
+        let mut bytes = Vec::new();
+        if self.extensions.is_empty() {
+            self.encoder.encode(dataframe, &mut bytes);
+        } else {
+            let mut dataframe = dataframe.clone();
+            for extension in &mut self.extensions {
+                extension.encode(&mut dataframe)?;
+            }
+            self.encoder.encode(&dataframe, &mut bytes);
+        }
+        self.socket.write_all(&bytes)?;
+        Ok(())
+    }
+
+    pub fn send_text(&mut self, text: &str) -> Result<(), WebSocketError> {
+        self.write_dataframe(&DataFrame::new(OpCode::Text, text.as_bytes().to_vec()))
+    }
+
+    pub fn send_binary(&mut self, data: &[u8]) -> Result<(), WebSocketError> {
+        self.write_dataframe(&DataFrame::new(OpCode::Binary, data.to_vec()))
+    }
+
+    pub fn send_ping(&mut self, data: &[u8]) -> Result<(), WebSocketError> {
+        self.write_dataframe(&DataFrame::new(OpCode::Ping, data.to_vec()))
     }
-}
 
-/// Gets the bit at position `position`. Positions are assumed to be big endian, so the 7th
-/// position is the most significant bit
-fn bit(byte: u8, position: u8) -> bool {
-    ((byte >> position) & 1) != 0
+    pub fn send_pong(&mut self, data: &[u8]) -> Result<(), WebSocketError> {
+        self.write_dataframe(&DataFrame::new(OpCode::Pong, data.to_vec()))
+    }
 }
 
+/// A data message whose final fragment hasn't arrived yet
 #[derive(Debug)]
+struct PartialMessage {
+    opcode: OpCode,
+    payload: Vec<u8>,
+    /// How much of a text payload is known to be valid utf-8
+    utf8_checked: usize,
+}
+
+impl PartialMessage {
+    /// Adds a fragment to the message, as long as that keeps it within `max_size`. Text is
+    /// validated as it arrives so that invalid utf-8 fails straight away rather than once the
+    /// whole message is in.
+    fn extend(&mut self, fragment: Vec<u8>, max_size: u64) -> Result<(), WebSocketError> {
+        if (self.payload.len() + fragment.len()) as u64 > max_size {
+            return Err(WebSocketError::PayloadTooLarge);
+        }
+        self.payload.extend(fragment);
+        if self.opcode != OpCode::Text {
+            return Ok(());
+        }
+
+        match std::str::from_utf8(&self.payload[self.utf8_checked..]) {
+            Ok(_) => self.utf8_checked = self.payload.len(),
+            // A code point split across fragments ends the payload early without being invalid,
+            // the rest of it should arrive with the next fragment
+            Err(error) if error.error_len().is_none() => self.utf8_checked += error.valid_up_to(),
+            Err(_) => return Err(WebSocketError::InvalidUtf8),
+        }
+        Ok(())
+    }
+
+    fn into_message(self) -> Result<Message, WebSocketError> {
+        match self.opcode {
+            OpCode::Text => String::from_utf8(self.payload)
+                .map(Message::Text)
+                .map_err(|_| WebSocketError::InvalidUtf8),
+            _ => Ok(Message::Binary(self.payload)),
+        }
+    }
+}
+
+/// A complete message, reassembled from one or more dataframes
+#[derive(Debug, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// The peer closed the connection, with the status code it gave if any
+    Close(Option<CloseFrame>),
+}
+
+#[derive(Debug, Clone)]
 pub struct DataFrame {
     pub fin: bool,
-    // rsv1-3 can be ignored, they are for extensions TODO research what extensions are
-    rsv1: bool,
-    rsv2: bool,
-    rsv3: bool,
+    /// The reserved bits, which only an [`Extension`] can give a meaning to
+    pub rsv1: bool,
+    pub rsv2: bool,
+    pub rsv3: bool,
     pub opcode: OpCode,
     mask: bool,
     pub payload_length: u64,
@@ -121,23 +495,58 @@ pub struct DataFrame {
 }
 
 impl DataFrame {
+    /// Builds a single, unfragmented frame as sent by the server.
+    pub fn new(opcode: OpCode, payload: Vec<u8>) -> DataFrame {
+        DataFrame {
+            fin: true,
+            rsv1: false,
+            rsv2: false,
+            rsv3: false,
+            opcode,
+            mask: false,
+            payload_length: payload.len() as u64,
+            mask_key: [0; 4],
+            payload,
+        }
+    }
+
     pub fn get_message(&self) -> &Vec<u8> {
         &self.payload
     }
+
+    /// The masking key the frame arrived with, if it was masked
+    pub fn mask_key(&self) -> Option<[u8; 4]> {
+        self.mask.then_some(self.mask_key)
+    }
+
+    /// Encodes the frame for sending from the server, see [`FrameEncoder::encode`]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        FrameEncoder::new().encode(self, &mut bytes);
+        bytes
+    }
 }
 
 /// OpCode enum for the possible 4-bit opcodes
 /// Values outside the range of 4 bits are invalid
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum OpCode {
     Continuation = 0x0,
     Text = 0x1, // Encoded in utf-8
     Binary = 0x2,
+    Close = 0x8,
     Ping = 0x9,
     Pong = 0xA,
 }
 
+impl OpCode {
+    /// Control frames are the ones with the most significant bit of the opcode set
+    pub fn is_control(&self) -> bool {
+        *self as u8 & 0x8 != 0
+    }
+}
+
 impl TryFrom<u8> for OpCode {
     type Error = WebSocketError;
 
@@ -146,11 +555,12 @@ impl TryFrom<u8> for OpCode {
             0x0 => Ok(OpCode::Continuation),
             0x1 => Ok(OpCode::Text),
             0x2 => Ok(OpCode::Binary),
-            0x3..=0x7 => Err(WebSocketError::BadOpCode(value)),
+            0x3..=0x7 => Err(WebSocketError::ReservedOpCode(value)),
+            0x8 => Ok(OpCode::Close),
             0x9 => Ok(OpCode::Ping),
             0xA => Ok(OpCode::Pong),
-            0x10..=0xFF => Err(WebSocketError::BadOpCode(value)), // Op codes are only 4 bits
-            opcode => Err(WebSocketError::OpCodeNotImplemented(opcode)),
+            0xB..=0xF => Err(WebSocketError::ReservedOpCode(value)),
+            _ => Err(WebSocketError::BadOpCode(value)), // Op codes are only 4 bits
         }
     }
 }
@@ -158,10 +568,64 @@ impl TryFrom<u8> for OpCode {
 #[derive(Debug)]
 pub enum WebSocketError {
     BadOpCode(u8),
-    OpCodeNotImplemented(u8),
+    ReservedOpCode(u8),
+    ReservedBitsSet,
+    ControlFrameTooLarge,
+    FragmentedControlFrame,
     Io(std::io::Error),
     UnencodedMessage,
     BadPayloadLength,
+    UnexpectedContinuation,
+    UnfinishedMessage,
+    InvalidUtf8,
+    InvalidCloseCode(u16),
+    BadClosePayload,
+    CloseReasonTooLong,
+    ConnectionClosed,
+    PongTimeout,
+    /// The peer didn't answer our close frame before the close timeout
+    CloseTimeout,
+    PayloadTooLarge,
+    /// An extension couldn't decode or encode a frame
+    Extension(String),
+}
+
+impl WebSocketError {
+    /// Whether this is the stream timing out or, if nonblocking, having nothing to read yet.
+    /// These don't affect the connection and reading can be tried again.
+    pub fn is_timeout(&self) -> bool {
+        matches!(
+            self,
+            WebSocketError::Io(error)
+                if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+        )
+    }
+
+    /// The status code to close the connection with when this error occurs, if the error means
+    /// the connection has to be failed
+    pub fn close_code(&self) -> Option<CloseCode> {
+        match self {
+            WebSocketError::BadOpCode(_)
+            | WebSocketError::ReservedBitsSet
+            | WebSocketError::ControlFrameTooLarge
+            | WebSocketError::FragmentedControlFrame
+            | WebSocketError::UnencodedMessage
+            | WebSocketError::BadPayloadLength
+            | WebSocketError::UnexpectedContinuation
+            | WebSocketError::UnfinishedMessage
+            | WebSocketError::InvalidCloseCode(_)
+            | WebSocketError::BadClosePayload
+            | WebSocketError::Extension(_) => Some(CloseCode::ProtocolError),
+            WebSocketError::InvalidUtf8 => Some(CloseCode::InvalidPayload),
+            // Reserved data opcodes are a kind of data we can't handle, while reserved control
+            // opcodes can only be a broken peer
+            WebSocketError::ReservedOpCode(0x3..=0x7) => Some(CloseCode::UnsupportedData),
+            WebSocketError::ReservedOpCode(_) => Some(CloseCode::ProtocolError),
+            WebSocketError::PongTimeout => Some(CloseCode::GoingAway),
+            WebSocketError::PayloadTooLarge => Some(CloseCode::MessageTooBig),
+            _ => None,
+        }
+    }
 }
 
 impl Display for WebSocketError {
@@ -170,16 +634,58 @@ impl Display for WebSocketError {
             WebSocketError::BadOpCode(opcode) => {
                 write!(f, "Bad in opcode {} in dataframe", opcode)
             }
-            WebSocketError::OpCodeNotImplemented(opcode) => {
+            WebSocketError::ReservedOpCode(opcode) => {
+                write!(f, "Received reserved opcode {}", opcode)
+            }
+            WebSocketError::ReservedBitsSet => {
+                write!(f, "Reserved bits set without a negotiated extension")
+            }
+            WebSocketError::ControlFrameTooLarge => {
                 write!(
                     f,
-                    "Received opcode {} which hasn't yet been implemented",
-                    opcode
+                    "Control frame payload was longer than {} bytes",
+                    MAX_CONTROL_PAYLOAD_LENGTH
                 )
             }
+            WebSocketError::FragmentedControlFrame => write!(f, "Control frame was fragmented"),
             WebSocketError::Io(error) => error.fmt(f),
             WebSocketError::UnencodedMessage => write!(f, "Mask bit set to 0"),
             WebSocketError::BadPayloadLength => write!(f, "Payload length was > 2^63-1"),
+            WebSocketError::UnexpectedContinuation => {
+                write!(
+                    f,
+                    "Received a continuation frame with no message in progress"
+                )
+            }
+            WebSocketError::UnfinishedMessage => {
+                write!(
+                    f,
+                    "Received a new data frame before the last message finished"
+                )
+            }
+            WebSocketError::InvalidUtf8 => write!(f, "Text message was not valid utf-8"),
+            WebSocketError::InvalidCloseCode(code) => {
+                write!(f, "Invalid close status code {}", code)
+            }
+            WebSocketError::BadClosePayload => {
+                write!(f, "Close frame payload was too short to hold a status code")
+            }
+            WebSocketError::CloseReasonTooLong => {
+                write!(
+                    f,
+                    "Close reason was longer than {} bytes",
+                    MAX_CLOSE_REASON_LENGTH
+                )
+            }
+            WebSocketError::ConnectionClosed => write!(f, "The connection has been closed"),
+            WebSocketError::PongTimeout => write!(f, "No pong received before the timeout"),
+            WebSocketError::CloseTimeout => {
+                write!(f, "No close received in reply before the timeout")
+            }
+            WebSocketError::PayloadTooLarge => {
+                write!(f, "Frame or message was larger than the configured limit")
+            }
+            WebSocketError::Extension(reason) => write!(f, "Extension failed, {reason}"),
         }
     }
 }
@@ -191,3 +697,512 @@ impl From<std::io::Error> for WebSocketError {
         WebSocketError::Io(value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+
+    /// Connects a client socket to a `WebSocket` over the loopback interface
+    fn socket_pair() -> (TcpStream, WebSocket<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, WebSocket::new(server))
+    }
+
+    /// Builds a masked frame as a client would send it. Only short payloads are supported.
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask_key = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![(fin as u8) << 7 | opcode, 0x80 | payload.len() as u8];
+        frame.extend(mask_key);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(index, byte)| byte ^ mask_key[index % 4]),
+        );
+        frame
+    }
+
+    #[test]
+    fn short_frame_encoding_works() {
+        let encoded = DataFrame::new(OpCode::Text, "Hello".into()).to_bytes();
+        let expected = [0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+
+        assert_eq!(encoded, expected);
+    }
+
+    #[test]
+    fn medium_frame_encoding_works() {
+        let encoded = DataFrame::new(OpCode::Binary, vec![0xAB; 256]).to_bytes();
+
+        assert_eq!(encoded[..4], [0x82, 126, 0x01, 0x00]);
+        assert_eq!(encoded.len(), 4 + 256);
+    }
+
+    #[test]
+    fn long_frame_encoding_works() {
+        let encoded = DataFrame::new(OpCode::Binary, vec![0xAB; 65536]).to_bytes();
+
+        assert_eq!(encoded[..10], [0x82, 127, 0, 0, 0, 0, 0, 0x01, 0x00, 0x00]);
+        assert_eq!(encoded.len(), 10 + 65536);
+    }
+
+    #[test]
+    fn send_text_works() {
+        let (mut client, mut ws) = socket_pair();
+        ws.send_text("Hello").unwrap();
+
+        let mut received = [0u8; 7];
+        client.read_exact(&mut received).unwrap();
+
+        assert_eq!(received, [0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f]);
+    }
+
+    #[test]
+    fn read_dataframe_unmasks_payload() {
+        let (mut client, mut ws) = socket_pair();
+        // Masked "Hello" from RFC 6455 section 5.7
+        client
+            .write_all(&[
+                0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+            ])
+            .unwrap();
+
+        let df = ws.read_dataframe().unwrap();
+
+        assert_eq!(df.opcode, OpCode::Text);
+        assert_eq!(df.get_message(), b"Hello");
+        assert_eq!(df.mask_key(), Some([0x37, 0xfa, 0x21, 0x3d]));
+    }
+
+    #[test]
+    fn read_message_reassembles_fragments() {
+        let (mut client, mut ws) = socket_pair();
+        client.write_all(&client_frame(false, 0x1, b"Hel")).unwrap();
+        client
+            .write_all(&client_frame(false, 0x0, b"lo, "))
+            .unwrap();
+        client
+            .write_all(&client_frame(true, 0x0, b"world"))
+            .unwrap();
+
+        assert_eq!(
+            ws.read_message().unwrap(),
+            Message::Text("Hello, world".into())
+        );
+    }
+
+    #[test]
+    fn read_message_passes_through_control_frames() {
+        let (mut client, mut ws) = socket_pair();
+        client
+            .write_all(&client_frame(false, 0x2, &[1, 2]))
+            .unwrap();
+        client.write_all(&client_frame(true, 0x9, b"ping")).unwrap();
+        client.write_all(&client_frame(true, 0x0, &[3])).unwrap();
+
+        assert_eq!(ws.read_message().unwrap(), Message::Ping(b"ping".to_vec()));
+        assert_eq!(ws.read_message().unwrap(), Message::Binary(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn read_message_rejects_unexpected_continuation() {
+        let (mut client, mut ws) = socket_pair();
+        client.write_all(&client_frame(true, 0x0, b"oops")).unwrap();
+
+        assert!(matches!(
+            ws.read_message(),
+            Err(WebSocketError::UnexpectedContinuation)
+        ));
+    }
+
+    #[test]
+    fn read_message_rejects_interleaved_data_frames() {
+        let (mut client, mut ws) = socket_pair();
+        client.write_all(&client_frame(false, 0x1, b"one")).unwrap();
+        client.write_all(&client_frame(true, 0x1, b"two")).unwrap();
+
+        assert!(matches!(
+            ws.read_message(),
+            Err(WebSocketError::UnfinishedMessage)
+        ));
+    }
+
+    #[test]
+    fn read_message_echoes_close() {
+        let (mut client, mut ws) = socket_pair();
+        client
+            .write_all(&client_frame(true, 0x8, &[0x03, 0xE8, b'b', b'y', b'e']))
+            .unwrap();
+
+        let expected = CloseFrame {
+            code: CloseCode::Normal,
+            reason: "bye".into(),
+        };
+        assert_eq!(ws.read_message().unwrap(), Message::Close(Some(expected)));
+        assert!(matches!(
+            ws.read_message(),
+            Err(WebSocketError::ConnectionClosed)
+        ));
+
+        drop(ws);
+        let mut echoed = Vec::new();
+        client.read_to_end(&mut echoed).unwrap();
+        assert_eq!(echoed, [0x88, 0x02, 0x03, 0xE8]);
+    }
+
+    #[test]
+    fn closed_connections_shut_down_their_stream() {
+        let (mut client, mut ws) = socket_pair();
+        client
+            .write_all(&client_frame(true, 0x8, &[0x03, 0xE8]))
+            .unwrap();
+
+        assert!(matches!(ws.read_message(), Ok(Message::Close(_))));
+
+        // The client sees the stream end while the `WebSocket` is still around
+        let mut echoed = Vec::new();
+        client.read_to_end(&mut echoed).unwrap();
+        assert_eq!(echoed, [0x88, 0x02, 0x03, 0xE8]);
+        drop(ws);
+    }
+
+    #[test]
+    fn close_runs_closing_handshake() {
+        let (mut client, mut ws) = socket_pair();
+        // The client's reply is already waiting when the server starts the handshake, anything
+        // sent before it is discarded
+        client.write_all(&client_frame(true, 0x1, b"late")).unwrap();
+        client
+            .write_all(&client_frame(true, 0x8, &[0x03, 0xE9]))
+            .unwrap();
+
+        ws.close(CloseCode::GoingAway, "done").unwrap();
+        assert!(matches!(
+            ws.send_text("too late"),
+            Err(WebSocketError::ConnectionClosed)
+        ));
+
+        drop(ws);
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).unwrap();
+        assert_eq!(received, [0x88, 0x06, 0x03, 0xE9, b'd', b'o', b'n', b'e']);
+    }
+
+    #[test]
+    fn close_gives_up_when_the_peer_never_replies() {
+        let (mut client, ws) = socket_pair();
+        let mut ws = WebSocket::with_config(
+            ws.into_inner(),
+            WebSocketConfig {
+                close_timeout: Duration::from_millis(20),
+                ..Default::default()
+            },
+        );
+        ws.get_ref()
+            .set_read_timeout(Some(Duration::from_millis(5)))
+            .unwrap();
+
+        assert!(matches!(
+            ws.close(CloseCode::Normal, ""),
+            Err(WebSocketError::CloseTimeout)
+        ));
+        assert!(matches!(
+            ws.read_message(),
+            Err(WebSocketError::ConnectionClosed)
+        ));
+
+        drop(ws);
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).unwrap();
+        assert_eq!(received, [0x88, 0x02, 0x03, 0xE8]);
+    }
+
+    #[test]
+    fn read_message_answers_pings() {
+        let (mut client, mut ws) = socket_pair();
+        client.write_all(&client_frame(true, 0x9, b"hi")).unwrap();
+
+        assert_eq!(ws.read_message().unwrap(), Message::Ping(b"hi".to_vec()));
+
+        let mut pong = [0u8; 4];
+        client.read_exact(&mut pong).unwrap();
+        assert_eq!(pong, [0x8A, 0x02, b'h', b'i']);
+    }
+
+    /// Reads like a caller using read timeouts would, retrying until something other than a
+    /// timeout happens
+    fn read_past_timeouts(ws: &mut WebSocket<TcpStream>) -> Result<Message, WebSocketError> {
+        loop {
+            match ws.read_message() {
+                Err(error) if error.is_timeout() => continue,
+                result => return result,
+            }
+        }
+    }
+
+    #[test]
+    fn heartbeat_pings_and_times_out() {
+        let (mut client, mut ws) = socket_pair();
+        ws.get_ref()
+            .set_read_timeout(Some(Duration::from_millis(5)))
+            .unwrap();
+        ws.set_heartbeat(Some(Heartbeat {
+            interval: Duration::from_millis(20),
+            timeout: Duration::from_millis(20),
+        }));
+
+        assert!(matches!(
+            read_past_timeouts(&mut ws),
+            Err(WebSocketError::PongTimeout)
+        ));
+
+        drop(ws);
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).unwrap();
+        assert_eq!(received[..2], [0x89, 0x00]);
+        assert_eq!(received[2..], [0x88, 0x02, 0x03, 0xE9]);
+    }
+
+    #[test]
+    fn heartbeat_pong_clears_awaiting_pong() {
+        let (mut client, mut ws) = socket_pair();
+        ws.get_ref()
+            .set_read_timeout(Some(Duration::from_millis(5)))
+            .unwrap();
+        ws.set_heartbeat(Some(Heartbeat {
+            interval: Duration::from_millis(20),
+            timeout: Duration::from_secs(10),
+        }));
+
+        let reader = std::thread::spawn(move || {
+            let mut ping = [0u8; 2];
+            client.read_exact(&mut ping).unwrap();
+            client.write_all(&client_frame(true, 0xA, b"")).unwrap();
+            client
+        });
+
+        assert_eq!(
+            read_past_timeouts(&mut ws).unwrap(),
+            Message::Pong(Vec::new())
+        );
+        assert!(!ws.is_awaiting_pong());
+        reader.join().unwrap();
+    }
+
+    #[test]
+    fn oversized_frame_is_rejected_before_payload() {
+        let (mut client, ws) = socket_pair();
+        let mut ws = WebSocket::with_config(
+            ws.into_inner(),
+            WebSocketConfig {
+                max_frame_size: 4,
+                ..Default::default()
+            },
+        );
+        // Only the header is sent, so reading the payload would block forever
+        client.write_all(&[0x82, 0x80 | 125]).unwrap();
+
+        assert!(matches!(
+            ws.read_message(),
+            Err(WebSocketError::PayloadTooLarge)
+        ));
+
+        drop(ws);
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).unwrap();
+        assert_eq!(received, [0x88, 0x02, 0x03, 0xF1]);
+    }
+
+    #[test]
+    fn oversized_message_is_rejected() {
+        let (mut client, ws) = socket_pair();
+        let mut ws = WebSocket::with_config(
+            ws.into_inner(),
+            WebSocketConfig {
+                max_message_size: 5,
+                ..Default::default()
+            },
+        );
+        client
+            .write_all(&client_frame(false, 0x2, &[1, 2, 3]))
+            .unwrap();
+        client
+            .write_all(&client_frame(true, 0x0, &[4, 5, 6]))
+            .unwrap();
+
+        assert!(matches!(
+            ws.read_message(),
+            Err(WebSocketError::PayloadTooLarge)
+        ));
+    }
+
+    /// Repeats every payload sent with rsv1 set ten times over, the way decompression can turn a
+    /// small frame into a large one
+    #[derive(Debug)]
+    struct Inflate;
+
+    impl Extension for Inflate {
+        fn name(&self) -> &str {
+            "x-inflate"
+        }
+
+        fn rsv_bits(&self) -> RsvBits {
+            RsvBits {
+                rsv1: true,
+                ..RsvBits::NONE
+            }
+        }
+
+        fn negotiate(&mut self, _params: &[ExtensionParam]) -> Option<Vec<ExtensionParam>> {
+            Some(Vec::new())
+        }
+
+        fn decode(&mut self, frame: &mut DataFrame) -> Result<(), WebSocketError> {
+            if frame.rsv1 {
+                frame.payload = frame.payload.repeat(10);
+                frame.rsv1 = false;
+            }
+            Ok(())
+        }
+    }
+
+    /// A `WebSocket` using [`Inflate`] with the given limits
+    fn inflating(config: WebSocketConfig) -> (TcpStream, WebSocket<TcpStream>) {
+        let (client, ws) = socket_pair();
+        let mut ws = WebSocket::with_config(ws.into_inner(), config);
+        ws.set_extensions(vec![Box::new(Inflate)]);
+        (client, ws)
+    }
+
+    #[test]
+    fn limits_apply_to_decoded_frames() {
+        let (mut client, mut ws) = inflating(WebSocketConfig {
+            max_frame_size: 16,
+            ..Default::default()
+        });
+        let mut frame = client_frame(true, 0x2, &[1, 2, 3, 4]);
+        frame[0] |= 0x40;
+        client.write_all(&frame).unwrap();
+
+        assert!(matches!(
+            ws.read_message(),
+            Err(WebSocketError::PayloadTooLarge)
+        ));
+        let mut received = [0; 4];
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(received, [0x88, 0x02, 0x03, 0xF1]);
+
+        let (mut client, mut ws) = inflating(WebSocketConfig {
+            max_message_size: 50,
+            ..Default::default()
+        });
+        let mut first = client_frame(false, 0x2, &[1, 2, 3]);
+        first[0] |= 0x40;
+        let mut last = client_frame(true, 0x0, &[4, 5, 6]);
+        last[0] |= 0x40;
+        client.write_all(&[first, last].concat()).unwrap();
+
+        assert!(matches!(
+            ws.read_message(),
+            Err(WebSocketError::PayloadTooLarge)
+        ));
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(received, [0x88, 0x02, 0x03, 0xF1]);
+    }
+
+    /// Sends `frame` from the client and returns the error it caused along with the close frame
+    /// the server sent back
+    fn read_invalid_frame(frame: &[u8]) -> (WebSocketError, [u8; 4]) {
+        let (mut client, mut ws) = socket_pair();
+        client.write_all(frame).unwrap();
+
+        let error = ws.read_message().unwrap_err();
+        let mut received = [0; 4];
+        client.read_exact(&mut received).unwrap();
+        (error, received)
+    }
+
+    #[test]
+    fn reserved_bits_are_rejected() {
+        let mut frame = client_frame(true, 0x1, b"rsv");
+        frame[0] |= 0x40;
+        let (error, received) = read_invalid_frame(&frame);
+
+        assert!(matches!(error, WebSocketError::ReservedBitsSet));
+        assert_eq!(received, [0x88, 0x02, 0x03, 0xEA]);
+    }
+
+    #[test]
+    fn oversized_control_frame_is_rejected() {
+        let (error, received) = read_invalid_frame(&[0x89, 0x80 | 126, 0x00, 0x7E]);
+
+        assert!(matches!(error, WebSocketError::ControlFrameTooLarge));
+        assert_eq!(received, [0x88, 0x02, 0x03, 0xEA]);
+    }
+
+    #[test]
+    fn fragmented_control_frame_is_rejected() {
+        let (error, received) = read_invalid_frame(&client_frame(false, 0x9, b"ping"));
+
+        assert!(matches!(error, WebSocketError::FragmentedControlFrame));
+        assert_eq!(received, [0x88, 0x02, 0x03, 0xEA]);
+    }
+
+    #[test]
+    fn reserved_opcodes_are_rejected() {
+        let (error, received) = read_invalid_frame(&client_frame(true, 0xB, b""));
+        assert!(matches!(error, WebSocketError::ReservedOpCode(0xB)));
+        assert_eq!(received, [0x88, 0x02, 0x03, 0xEA]);
+
+        let (error, received) = read_invalid_frame(&client_frame(true, 0x3, b""));
+        assert!(matches!(error, WebSocketError::ReservedOpCode(0x3)));
+        assert_eq!(received, [0x88, 0x02, 0x03, 0xEB]);
+    }
+
+    #[test]
+    fn oversized_ping_is_not_sent() {
+        let (_client, mut ws) = socket_pair();
+
+        assert!(matches!(
+            ws.send_ping(&[0; 126]),
+            Err(WebSocketError::ControlFrameTooLarge)
+        ));
+    }
+
+    #[test]
+    fn code_point_split_across_fragments_is_accepted() {
+        let (mut client, mut ws) = socket_pair();
+        let euro = "€".as_bytes();
+        client
+            .write_all(&client_frame(false, 0x1, &euro[..1]))
+            .unwrap();
+        client
+            .write_all(&client_frame(false, 0x0, &euro[1..2]))
+            .unwrap();
+        client
+            .write_all(&client_frame(true, 0x0, &euro[2..]))
+            .unwrap();
+
+        assert_eq!(ws.read_message().unwrap(), Message::Text("€".into()));
+    }
+
+    #[test]
+    fn invalid_utf8_fails_before_message_ends() {
+        // The final fragment never arrives, so this only returns if the first one is rejected
+        let (error, received) = read_invalid_frame(&client_frame(false, 0x1, &[b'a', 0xFF]));
+
+        assert!(matches!(error, WebSocketError::InvalidUtf8));
+        assert_eq!(received, [0x88, 0x02, 0x03, 0xEF]);
+    }
+
+    #[test]
+    fn truncated_code_point_is_rejected() {
+        let euro = "€".as_bytes();
+        let (error, _) = read_invalid_frame(&client_frame(true, 0x1, &euro[..2]));
+
+        assert!(matches!(error, WebSocketError::InvalidUtf8));
+    }
+}
@@ -0,0 +1,28 @@
+use std::io::{self, Cursor};
+use std::net::TcpStream;
+
+/// A stream whose writing half can be closed on its own, so that the peer sees the end of the
+/// stream once the closing handshake is over even while the `WebSocket` is still held on to.
+///
+/// Streams that have nothing to close, such as in-memory transports, can implement this without
+/// overriding anything.
+pub trait Shutdown {
+    /// Closes the writing half of the stream. Everything already written is still delivered.
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Shutdown for TcpStream {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        TcpStream::shutdown(self, std::net::Shutdown::Write)
+    }
+}
+
+impl<T> Shutdown for Cursor<T> {}
+
+impl<S: Shutdown + ?Sized> Shutdown for Box<S> {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        (**self).shutdown_write()
+    }
+}
@@ -0,0 +1,556 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use tarnished_sockets::http::HttpRequest;
+#[cfg(target_os = "linux")]
+use tarnished_sockets::server::EventLoop;
+use tarnished_sockets::server::{Connection, Handler, Router, Server, ServerConfig, VirtualHosts};
+use tarnished_sockets::websocket::{
+    CloseCode, CloseFrame, DataFrame, Extension, ExtensionFactory, ExtensionParam, Heartbeat,
+    Message, RsvBits, WebSocketError,
+};
+
+struct Echo;
+
+impl Handler for Echo {
+    fn on_message(&self, connection: &mut Connection, message: Message) {
+        if let Message::Text(text) = message {
+            connection.send_text(&text).unwrap();
+        }
+    }
+}
+
+/// Greets each client with the room it joined and the query it joined with
+struct Rooms;
+
+impl Handler for Rooms {
+    fn on_open(&self, connection: &mut Connection, _request: &HttpRequest) {
+        let greeting = format!(
+            "room {} {}",
+            connection.param("id").unwrap(),
+            connection.query().get("name").unwrap()
+        );
+        connection.send_text(&greeting).unwrap();
+    }
+}
+
+/// Reports every callback it gets, and closes the connection when asked to by a message
+struct Recorder {
+    events: Mutex<Sender<String>>,
+}
+
+impl Recorder {
+    fn new() -> (Recorder, Receiver<String>) {
+        let (sender, receiver) = mpsc::channel();
+        let recorder = Recorder {
+            events: Mutex::new(sender),
+        };
+        (recorder, receiver)
+    }
+
+    fn record(&self, event: String) {
+        self.events.lock().unwrap().send(event).unwrap();
+    }
+}
+
+impl Handler for Recorder {
+    fn on_open(&self, _connection: &mut Connection, request: &HttpRequest) {
+        self.record(format!("open {}", request.uri));
+    }
+
+    fn on_message(&self, connection: &mut Connection, message: Message) {
+        if message == Message::Text("close".into()) {
+            connection.close(CloseCode::GoingAway, "asked to").unwrap();
+        }
+        self.record(format!("message {message:?}"));
+    }
+
+    fn on_close(&self, peer_addr: SocketAddr, frame: CloseFrame) {
+        assert!(peer_addr.ip().is_loopback());
+        self.record(format!("close {:?}", frame.code));
+    }
+}
+
+/// Sends as much as it can as soon as a connection opens
+struct Flood;
+
+impl Handler for Flood {
+    fn on_open(&self, connection: &mut Connection, _request: &HttpRequest) {
+        while connection.send_binary(&[0; 64 * 1024]).is_ok() {}
+    }
+}
+
+/// Inverts the payload of data frames, marking the inverted ones with RSV1
+#[derive(Debug)]
+struct Invert;
+
+impl Extension for Invert {
+    fn name(&self) -> &str {
+        "x-invert"
+    }
+
+    fn rsv_bits(&self) -> RsvBits {
+        RsvBits {
+            rsv1: true,
+            ..RsvBits::NONE
+        }
+    }
+
+    /// Takes no parameters
+    fn negotiate(&mut self, params: &[ExtensionParam]) -> Option<Vec<ExtensionParam>> {
+        params.is_empty().then(Vec::new)
+    }
+
+    fn decode(&mut self, frame: &mut DataFrame) -> Result<(), WebSocketError> {
+        if frame.rsv1 {
+            frame.payload.iter_mut().for_each(|byte| *byte = !*byte);
+            frame.rsv1 = false;
+        }
+        Ok(())
+    }
+
+    fn encode(&mut self, frame: &mut DataFrame) -> Result<(), WebSocketError> {
+        if !frame.opcode.is_control() {
+            frame.payload.iter_mut().for_each(|byte| *byte = !*byte);
+            frame.rsv1 = true;
+        }
+        Ok(())
+    }
+}
+
+/// Starts a server on an unused port, returning the address it serves on
+fn start_server(config: ServerConfig) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || Server::with_config(Echo, config).serve(listener));
+    addr
+}
+
+/// Reads a response up to the end of its headers
+fn read_response(stream: &mut TcpStream) -> String {
+    let mut response = Vec::new();
+    let mut byte = [0];
+    while !response.ends_with(b"\r\n\r\n") {
+        if stream.read(&mut byte).unwrap() == 0 {
+            break;
+        }
+        response.push(byte[0]);
+    }
+    String::from_utf8(response).unwrap()
+}
+
+fn connect(addr: SocketAddr) -> (TcpStream, String) {
+    connect_to(addr, "/chat")
+}
+
+fn connect_to(addr: SocketAddr, path: &str) -> (TcpStream, String) {
+    connect_as(addr, "localhost", path)
+}
+
+/// Sends a handshake for `path` on `host`
+fn connect_as(addr: SocketAddr, host: &str, path: &str) -> (TcpStream, String) {
+    connect_with(addr, path, &format!("Host: {host}\r\n"))
+}
+
+/// Sends a handshake for `path` with the extra `headers`, which have to include the `Host`,
+/// returning the stream and the response up to its headers
+fn connect_with(addr: SocketAddr, path: &str, headers: &str) -> (TcpStream, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let request = format!(
+        "GET {path} HTTP/1.1\r\n\
+         {headers}\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+         Sec-WebSocket-Version: 13\r\n\r\n"
+    );
+    stream.write_all(request.as_bytes()).unwrap();
+    let response = read_response(&mut stream);
+    (stream, response)
+}
+
+/// Sends a short masked frame as a client would
+fn send(stream: &mut TcpStream, opcode: u8, payload: &[u8]) {
+    let mask_key = [0x12, 0x34, 0x56, 0x78];
+    let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+    frame.extend(mask_key);
+    frame.extend(
+        payload
+            .iter()
+            .enumerate()
+            .map(|(index, byte)| byte ^ mask_key[index % 4]),
+    );
+    stream.write_all(&frame).unwrap();
+}
+
+/// Reads a short unmasked frame from the server, returning its first byte and payload
+fn receive(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+    let mut header = [0; 2];
+    stream.read_exact(&mut header).unwrap();
+    let mut payload = vec![0; header[1] as usize];
+    stream.read_exact(&mut payload).unwrap();
+    (header[0], payload)
+}
+
+/// Sends a text message and reads back the payload of the frame the server answers with
+fn echo(stream: &mut TcpStream, text: &str) -> Vec<u8> {
+    send(stream, 0x1, text.as_bytes());
+    receive(stream).1
+}
+
+/// Runs a connection through a [`Recorder`], checking each callback happens once and in order
+fn check_callbacks(addr: SocketAddr, events: Receiver<String>) {
+    let (mut stream, _) = connect(addr);
+    send(&mut stream, 0x1, b"hi");
+    send(&mut stream, 0x1, b"close");
+
+    let mut close = [0x03, 0xE9].to_vec();
+    close.extend(b"asked to");
+    assert_eq!(receive(&mut stream), (0x88, close));
+    send(&mut stream, 0x8, &[0x03, 0xE9]);
+
+    let events: Vec<String> = events.iter().take(4).collect();
+    assert_eq!(
+        events,
+        [
+            "open /chat",
+            "message Text(\"hi\")",
+            "message Text(\"close\")",
+            "close GoingAway",
+        ]
+    );
+}
+
+/// Has a [`Recorder`] close a connection that never answers the close, checking the connection
+/// is given up on
+fn check_close_timeout(addr: SocketAddr, events: Receiver<String>) {
+    let (mut stream, _) = connect(addr);
+    send(&mut stream, 0x1, b"close");
+
+    let events: Vec<String> = events.iter().take(3).collect();
+    assert_eq!(events[2], "close Abnormal");
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+}
+
+/// Has a [`Recorder`]'s connection fail with a protocol error, checking the handler is told the
+/// code that was sent
+fn check_failed_close(addr: SocketAddr, events: Receiver<String>) {
+    let (mut stream, _) = connect(addr);
+    // A reserved bit without an extension for it
+    send(&mut stream, 0x41, b"rsv");
+    assert_eq!(receive(&mut stream), (0x88, vec![0x03, 0xEA]));
+
+    let events: Vec<String> = events.iter().take(2).collect();
+    assert_eq!(events[1], "close ProtocolError");
+}
+
+/// A config that gives up quickly on closing handshakes
+fn close_timeout_config() -> ServerConfig {
+    let mut config = ServerConfig::default();
+    config.websocket.close_timeout = Duration::from_millis(100);
+    config
+}
+
+#[test]
+fn connections_are_served_concurrently() {
+    let addr = start_server(ServerConfig::default());
+
+    let (mut first, response) = connect(addr);
+    assert!(response.starts_with("HTTP/1.1 101"));
+    let (mut second, response) = connect(addr);
+    assert!(response.starts_with("HTTP/1.1 101"));
+
+    // The first connection is still open while the second one is served
+    assert_eq!(echo(&mut second, "second"), b"second");
+    assert_eq!(echo(&mut first, "first"), b"first");
+    assert_eq!(echo(&mut second, "again"), b"again");
+}
+
+#[test]
+fn connections_beyond_the_limit_are_refused() {
+    let addr = start_server(ServerConfig {
+        max_connections: 1,
+        ..Default::default()
+    });
+
+    let (mut first, response) = connect(addr);
+    assert!(response.starts_with("HTTP/1.1 101"));
+    let (_, response) = connect(addr);
+    assert!(response.starts_with("HTTP/1.1 503"));
+
+    assert_eq!(echo(&mut first, "still here"), b"still here");
+}
+
+#[test]
+fn idle_handshakes_give_up_their_slot() {
+    let addr = start_server(ServerConfig {
+        max_connections: 1,
+        handshake_timeout: Duration::from_millis(100),
+        ..Default::default()
+    });
+
+    // Only part of a request line, and nothing after it
+    let mut idle = TcpStream::connect(addr).unwrap();
+    idle.write_all(b"GET /ch").unwrap();
+    assert_eq!(idle.read(&mut [0]).unwrap(), 0);
+
+    // The slot is given back just after the idle connection is closed
+    thread::sleep(Duration::from_millis(50));
+    let (mut stream, response) = connect(addr);
+    assert!(response.starts_with("HTTP/1.1 101"));
+    assert_eq!(echo(&mut stream, "my turn"), b"my turn");
+}
+
+/// Sends something that isn't a websocket handshake and checks the server explains why it's
+/// hanging up
+fn send_bad_handshake(addr: SocketAddr) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"BREW /pot HTTP/1.1\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert!(response.ends_with("\r\n\r\nInvalid HTTP method in request\n"));
+}
+
+#[test]
+fn handler_callbacks_are_made_in_order() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (recorder, events) = Recorder::new();
+    thread::spawn(move || Server::new(recorder).serve(listener));
+
+    check_callbacks(addr, events);
+}
+
+#[test]
+fn unanswered_closes_time_out() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (recorder, events) = Recorder::new();
+    thread::spawn(move || Server::with_config(recorder, close_timeout_config()).serve(listener));
+
+    check_close_timeout(addr, events);
+}
+
+#[test]
+fn clients_that_stop_reading_give_up_their_slot() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut config = ServerConfig {
+        max_connections: 1,
+        ..Default::default()
+    };
+    config.websocket.heartbeat = Some(Heartbeat {
+        interval: Duration::from_secs(1),
+        timeout: Duration::from_millis(200),
+    });
+    thread::spawn(move || Server::with_config(Flood, config).serve(listener));
+
+    // Never reads what the server floods it with
+    let (_flooded, response) = connect(addr);
+    assert!(response.starts_with("HTTP/1.1 101"));
+
+    for _ in 0..50 {
+        thread::sleep(Duration::from_millis(100));
+        let (_, response) = connect(addr);
+        if response.starts_with("HTTP/1.1 101") {
+            return;
+        }
+    }
+    panic!("the connection that stopped reading still holds the only slot");
+}
+
+#[test]
+fn failed_connections_report_the_code_they_were_closed_with() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (recorder, events) = Recorder::new();
+    thread::spawn(move || Server::new(recorder).serve(listener));
+
+    check_failed_close(addr, events);
+}
+
+#[test]
+fn paths_are_routed_to_their_handlers() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let router = Router::new()
+        .route("/chat", Echo)
+        .route("/rooms/:id", Rooms);
+    thread::spawn(move || Server::new(router).serve(listener));
+
+    let (mut chat, _) = connect_to(addr, "/chat");
+    assert_eq!(echo(&mut chat, "hello"), b"hello");
+
+    let (mut room, _) = connect_to(addr, "/rooms/42?name=ann+b%C3%B8");
+    assert_eq!(receive(&mut room).1, "room 42 ann bø".as_bytes());
+
+    let (_, response) = connect_to(addr, "/feed");
+    assert!(response.starts_with("HTTP/1.1 404"));
+}
+
+#[test]
+fn hosts_are_routed_to_their_handlers() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let hosts = VirtualHosts::new().host("example.com", Echo).host(
+        "staging.example.com",
+        Router::new().route("/rooms/:id", Rooms),
+    );
+    thread::spawn(move || Server::new(hosts).serve(listener));
+
+    let (mut prod, _) = connect_as(addr, "example.com", "/rooms/1?name=ann");
+    assert_eq!(echo(&mut prod, "hello"), b"hello");
+
+    let (mut staging, _) = connect_as(addr, "Staging.Example.com:7878", "/rooms/1?name=ann");
+    assert_eq!(receive(&mut staging).1, b"room 1 ann");
+
+    let (_, response) = connect_as(addr, "localhost", "/rooms/1?name=ann");
+    assert!(response.starts_with("HTTP/1.1 404"));
+}
+
+#[test]
+fn negotiated_extensions_transform_frames() {
+    let addr = start_server(ServerConfig {
+        extensions: vec![ExtensionFactory::new(|| Box::new(Invert))],
+        ..Default::default()
+    });
+
+    let (mut stream, response) = connect_with(
+        addr,
+        "/chat",
+        "Host: localhost\r\nSec-WebSocket-Extensions: x-invert; level=1, x-invert\r\n",
+    );
+    assert!(response.contains("\r\nSec-WebSocket-Extensions: x-invert\r\n"));
+
+    // An RSV1 text frame, inverted by the client
+    let inverted: Vec<u8> = b"hello".iter().map(|byte| !byte).collect();
+    send(&mut stream, 0x41, &inverted);
+    assert_eq!(receive(&mut stream), (0xC1, inverted));
+
+    // Without the extension the reserved bit fails the connection
+    let (mut stream, response) = connect(addr);
+    assert!(!response.contains("Sec-WebSocket-Extensions"));
+    send(&mut stream, 0x41, b"hello");
+    assert_eq!(receive(&mut stream), (0x88, vec![0x03, 0xEA]));
+}
+
+#[test]
+fn bad_handshake_only_ends_its_connection() {
+    let addr = start_server(ServerConfig::default());
+
+    send_bad_handshake(addr);
+    let (mut stream, response) = connect(addr);
+    assert!(response.starts_with("HTTP/1.1 101"));
+    assert_eq!(echo(&mut stream, "still serving"), b"still serving");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn event_loop_serves_many_connections_from_one_thread() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || EventLoop::new(Echo).serve(listener));
+
+    let mut clients: Vec<TcpStream> = (0..200)
+        .map(|_| {
+            let (stream, response) = connect(addr);
+            assert!(response.starts_with("HTTP/1.1 101"));
+            stream
+        })
+        .collect();
+
+    // Every connection is still open and served, in whatever order they're used
+    for (index, client) in clients.iter_mut().enumerate().rev() {
+        let text = format!("client {index}");
+        assert_eq!(echo(client, &text), text.as_bytes());
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn event_loop_survives_bad_handshakes() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || EventLoop::new(Echo).serve(listener));
+
+    send_bad_handshake(addr);
+    let (mut stream, response) = connect(addr);
+    assert!(response.starts_with("HTTP/1.1 101"));
+    assert_eq!(echo(&mut stream, "still serving"), b"still serving");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn event_loop_drops_idle_handshakes() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        max_connections: 1,
+        handshake_timeout: Duration::from_millis(100),
+        ..Default::default()
+    };
+    thread::spawn(move || EventLoop::with_config(Echo, config).serve(listener));
+
+    let mut idle = TcpStream::connect(addr).unwrap();
+    idle.write_all(b"GET /ch").unwrap();
+    assert_eq!(idle.read(&mut [0]).unwrap(), 0);
+
+    let (mut stream, response) = connect(addr);
+    assert!(response.starts_with("HTTP/1.1 101"));
+    assert_eq!(echo(&mut stream, "my turn"), b"my turn");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn event_loop_drops_clients_that_stop_reading() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || EventLoop::new(Flood).serve(listener));
+
+    // Only returns once the server has given up on sending to the client
+    let (mut stream, response) = connect(addr);
+    assert!(response.starts_with("HTTP/1.1 101"));
+    let mut sent = Vec::new();
+    stream.read_to_end(&mut sent).unwrap();
+    assert!(!sent.is_empty());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn event_loop_makes_handler_callbacks_in_order() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (recorder, events) = Recorder::new();
+    thread::spawn(move || EventLoop::new(recorder).serve(listener));
+
+    check_callbacks(addr, events);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn event_loop_times_out_unanswered_closes() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (recorder, events) = Recorder::new();
+    thread::spawn(move || EventLoop::with_config(recorder, close_timeout_config()).serve(listener));
+
+    check_close_timeout(addr, events);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn event_loop_reports_the_code_failed_connections_were_closed_with() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (recorder, events) = Recorder::new();
+    thread::spawn(move || EventLoop::new(recorder).serve(listener));
+
+    check_failed_close(addr, events);
+}
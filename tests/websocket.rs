@@ -0,0 +1,152 @@
+use std::io::{self, Cursor, Read, Write};
+
+use tarnished_sockets::websocket::{
+    CloseCode, CloseFrame, DataFrame, Message, OpCode, Shutdown, WebSocket, WebSocketError,
+};
+
+/// An in-memory transport. Reads come from the bytes queued up by the test and everything written
+/// is collected so the test can check it.
+struct Duplex {
+    input: Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+impl Duplex {
+    fn new(input: Vec<u8>) -> Duplex {
+        Duplex {
+            input: Cursor::new(input),
+            output: Vec::new(),
+        }
+    }
+}
+
+impl Read for Duplex {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for Duplex {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Shutdown for Duplex {}
+
+/// Builds a masked frame as a client would send it
+fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mask_key = [0x12, 0x34, 0x56, 0x78];
+    let mut frame = vec![(fin as u8) << 7 | opcode];
+    match payload.len() {
+        0..=125 => frame.push(0x80 | payload.len() as u8),
+        126..=0xFFFF => {
+            frame.push(0x80 | 126);
+            frame.extend((payload.len() as u16).to_be_bytes());
+        }
+        _ => {
+            frame.push(0x80 | 127);
+            frame.extend((payload.len() as u64).to_be_bytes());
+        }
+    }
+    frame.extend(mask_key);
+    frame.extend(
+        payload
+            .iter()
+            .enumerate()
+            .map(|(index, byte)| byte ^ mask_key[index % 4]),
+    );
+    frame
+}
+
+fn websocket(frames: &[Vec<u8>]) -> WebSocket<Duplex> {
+    WebSocket::new(Duplex::new(frames.concat()))
+}
+
+#[test]
+fn reads_messages_of_every_length_form() {
+    let medium = vec![7; 300];
+    let long = vec![9; 70_000];
+    let mut ws = websocket(&[
+        client_frame(true, 0x1, b"short"),
+        client_frame(true, 0x2, &medium),
+        client_frame(true, 0x2, &long),
+    ]);
+
+    assert_eq!(ws.read_message().unwrap(), Message::Text("short".into()));
+    assert_eq!(ws.read_message().unwrap(), Message::Binary(medium));
+    assert_eq!(ws.read_message().unwrap(), Message::Binary(long));
+}
+
+#[test]
+fn fragmented_message_with_ping_in_between() {
+    let mut ws = websocket(&[
+        client_frame(false, 0x1, b"frag"),
+        client_frame(true, 0x9, b"are you there"),
+        client_frame(true, 0x0, b"mented"),
+    ]);
+
+    assert_eq!(
+        ws.read_message().unwrap(),
+        Message::Ping(b"are you there".to_vec())
+    );
+    assert_eq!(
+        ws.read_message().unwrap(),
+        Message::Text("fragmented".into())
+    );
+
+    let pong = DataFrame::new(OpCode::Pong, b"are you there".to_vec()).to_bytes();
+    assert_eq!(ws.get_ref().output, pong);
+}
+
+#[test]
+fn sent_frames_are_unmasked() {
+    let mut ws = websocket(&[]);
+    ws.send_text("hi").unwrap();
+    ws.send_binary(&[0; 200]).unwrap();
+
+    let output = &ws.get_ref().output;
+    assert_eq!(output[..4], [0x81, 0x02, b'h', b'i']);
+    assert_eq!(output[4..8], [0x82, 126, 0x00, 200]);
+    assert_eq!(output.len(), 8 + 200);
+}
+
+#[test]
+fn peer_close_is_echoed() {
+    let mut ws = websocket(&[client_frame(true, 0x8, &[0x0F, 0xA0, b'o', b'k'])]);
+
+    let expected = CloseFrame {
+        code: CloseCode::Application(4000),
+        reason: "ok".into(),
+    };
+    assert_eq!(ws.read_message().unwrap(), Message::Close(Some(expected)));
+    assert_eq!(ws.get_ref().output, [0x88, 0x02, 0x0F, 0xA0]);
+}
+
+#[test]
+fn protocol_error_sends_close() {
+    let mut ws = websocket(&[client_frame(true, 0x0, b"no message")]);
+
+    assert!(matches!(
+        ws.read_message(),
+        Err(WebSocketError::UnexpectedContinuation)
+    ));
+    assert_eq!(ws.get_ref().output, [0x88, 0x02, 0x03, 0xEA]);
+    assert!(matches!(
+        ws.read_message(),
+        Err(WebSocketError::ConnectionClosed)
+    ));
+}
+
+#[test]
+fn end_of_stream_is_an_error() {
+    let mut frame = client_frame(true, 0x1, b"cut off");
+    frame.truncate(frame.len() - 2);
+    let mut ws = websocket(&[frame]);
+
+    assert!(matches!(ws.read_message(), Err(WebSocketError::Io(_))));
+}